@@ -1,9 +1,17 @@
+use crate::cpu::call_stack::CallStack;
 use crate::cpu::register::Registers;
+use crate::cpu::instruction::MCycles;
+use crate::cpu::trace::Tracer;
+use crate::dma::DMA;
+use crate::ppu::PPU;
 use crate::ram::Ram;
 use crate::timer::Timer;
 
+pub mod call_stack;
+pub mod disassembler;
 pub mod instruction;
 pub mod register;
+pub mod trace;
 mod carry;
 
 #[derive(PartialEq, Eq, Debug)]
@@ -19,13 +27,30 @@ enum Mode {
 	LowPower,
 	NormalSpeed,
 	DoubleSpeed,
+	/// Hung after executing an illegal opcode. Only a reset recovers from this
+	Locked,
+}
+
+/// A single M-cycle as seen from the memory bus
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum BusActivity {
+	Read { address: u16, value: u8 },
+	Write { address: u16, value: u8 },
+	/// A cycle spent without touching memory
+	Internal,
 }
 
 pub struct CPU {
 	pub registers: Registers,
 	pub ram: Ram,
 	pub timer: Timer,
+	ppu: PPU,
+	dma: DMA,
 	ime: Ime,
 	mode: Mode,
 	halt_bug_active: bool,
+	bus_cycles: MCycles,
+	tracer: Option<Tracer>,
+	bus_log: Option<Vec<BusActivity>>,
+	call_stack: Option<CallStack>,
 }
\ No newline at end of file
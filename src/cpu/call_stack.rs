@@ -0,0 +1,166 @@
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct CallFrame {
+	/// Address of the CALL/RST, or the interrupted instruction for interrupts
+	pub caller_pc: u16,
+	pub target: u16,
+	/// ROM bank mapped into 0x4000-0x7FFF when the call happened
+	pub rom_bank: u16,
+	pub interrupt: bool,
+	pub return_address: u16,
+	/// Where the return address was pushed to
+	pub stack_pointer: u16,
+}
+
+impl fmt::Display for CallFrame {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let kind = if self.interrupt { "interrupt" } else { "call" };
+		write!(
+			f,
+			"{:02X}:{:04X} from {:04X} ({}, returns to {:04X}, SP {:04X})",
+			self.rom_bank, self.target, self.caller_pc, kind, self.return_address, self.stack_pointer
+		)
+	}
+}
+
+/// Ways the program can move SP or the return address behind the call stack's back
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum StackImbalance {
+	/// Frames whose return address was never popped, such as after SP is reloaded or a return address is POPped
+	AbandonedFrames { frames: Vec<CallFrame> },
+	/// A RET/RETI popped a different address than the one that was pushed
+	ReturnAddressChanged { frame: CallFrame, actual: u16 },
+	/// A RET/RETI without a matching call, such as when a pushed address is used as a jump
+	UnmatchedReturn { pc: u16, stack_pointer: u16, return_address: u16 },
+}
+
+/// Shadow of the calls that are currently in progress, maintained next to the real stack
+#[derive(Default)]
+pub struct CallStack {
+	frames: Vec<CallFrame>,
+	imbalances: Vec<StackImbalance>,
+}
+
+impl CallStack {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Frames from the outermost call to the most recent one
+	pub fn frames(&self) -> &[CallFrame] {
+		&self.frames
+	}
+
+	pub fn imbalances(&self) -> &[StackImbalance] {
+		&self.imbalances
+	}
+
+	pub fn take_imbalances(&mut self) -> Vec<StackImbalance> {
+		std::mem::take(&mut self.imbalances)
+	}
+
+	pub fn push(&mut self, frame: CallFrame) {
+		// The stack grows downwards, so frames at or below the new one have had their slot reused
+		self.abandon_frames_below(frame.stack_pointer.wrapping_add(1));
+		self.frames.push(frame);
+	}
+
+	/// Records a return that popped `return_address` from `stack_pointer`
+	pub fn pop(&mut self, pc: u16, stack_pointer: u16, return_address: u16) {
+		// Returning from further up the stack means the frames in between were abandoned
+		self.abandon_frames_below(stack_pointer);
+
+		match self.frames.last() {
+			Some(frame) if frame.stack_pointer == stack_pointer => {
+				let frame = self.frames.pop().unwrap();
+				if frame.return_address != return_address {
+					self.imbalances.push(StackImbalance::ReturnAddressChanged { frame, actual: return_address });
+				}
+			}
+			_ => self.imbalances.push(StackImbalance::UnmatchedReturn { pc, stack_pointer, return_address }),
+		}
+	}
+
+	fn abandon_frames_below(&mut self, stack_pointer: u16) {
+		let first_abandoned = self.frames.iter().position(|frame| frame.stack_pointer < stack_pointer);
+		if let Some(first_abandoned) = first_abandoned {
+			let frames = self.frames.split_off(first_abandoned);
+			self.imbalances.push(StackImbalance::AbandonedFrames { frames });
+		}
+	}
+}
+
+/// Prints a backtrace with the most recent call first
+impl fmt::Display for CallStack {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		for (depth, frame) in self.frames.iter().rev().enumerate() {
+			writeln!(f, "#{depth} {frame}")?;
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn frame(caller_pc: u16, target: u16, stack_pointer: u16) -> CallFrame {
+		CallFrame {
+			caller_pc,
+			target,
+			rom_bank: 1,
+			interrupt: false,
+			return_address: caller_pc + 3,
+			stack_pointer,
+		}
+	}
+
+	#[test]
+	fn test_balanced_calls() {
+		let mut call_stack = CallStack::new();
+		call_stack.push(frame(0x0100, 0x0200, 0xFFFC));
+		call_stack.push(frame(0x0210, 0x0300, 0xFFFA));
+		assert_eq!(call_stack.frames().len(), 2);
+		assert_eq!(call_stack.to_string().lines().next().unwrap(), "#0 01:0300 from 0210 (call, returns to 0213, SP FFFA)");
+
+		call_stack.pop(0x0310, 0xFFFA, 0x0213);
+		call_stack.pop(0x0220, 0xFFFC, 0x0103);
+		assert!(call_stack.frames().is_empty());
+		assert!(call_stack.imbalances().is_empty());
+	}
+
+	#[test]
+	fn test_imbalances() {
+		let mut call_stack = CallStack::new();
+		call_stack.push(frame(0x0100, 0x0200, 0xFFFC));
+		call_stack.push(frame(0x0210, 0x0300, 0xFFFA));
+
+		// The inner return address is popped off, then the outer call returns
+		call_stack.pop(0x0220, 0xFFFC, 0x0103);
+		assert!(call_stack.frames().is_empty());
+		assert_eq!(call_stack.take_imbalances(), vec![
+			StackImbalance::AbandonedFrames { frames: vec![frame(0x0210, 0x0300, 0xFFFA)] },
+		]);
+
+		// A return address that was modified on the stack
+		call_stack.push(frame(0x0100, 0x0200, 0xFFFC));
+		call_stack.pop(0x0220, 0xFFFC, 0x4000);
+		assert_eq!(call_stack.take_imbalances(), vec![
+			StackImbalance::ReturnAddressChanged { frame: frame(0x0100, 0x0200, 0xFFFC), actual: 0x4000 },
+		]);
+
+		// Pushing an address and returning to it
+		call_stack.pop(0x0220, 0xFFFC, 0x4000);
+		assert_eq!(call_stack.take_imbalances(), vec![
+			StackImbalance::UnmatchedReturn { pc: 0x0220, stack_pointer: 0xFFFC, return_address: 0x4000 },
+		]);
+
+		// SP is reloaded above an unfinished call, then a new call reuses its slot
+		call_stack.push(frame(0x0100, 0x0200, 0xFFFA));
+		call_stack.push(frame(0x0150, 0x0250, 0xFFFC));
+		assert_eq!(call_stack.frames(), &[frame(0x0150, 0x0250, 0xFFFC)]);
+		assert_eq!(call_stack.imbalances().len(), 1);
+	}
+}
@@ -0,0 +1,153 @@
+use std::fmt;
+use crate::cpu::instruction::{cb_operation, operation, MCycles};
+use crate::ram::Ram;
+
+const R8_NAMES: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const R16_NAMES: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const R16_STACK_NAMES: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const CC_NAMES: [&str; 4] = ["NZ", "Z", "NC", "C"];
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DisassembledInstruction {
+	pub address: u16,
+	pub bytes: Vec<u8>,
+	pub mnemonic: String,
+	pub operands: Vec<String>,
+	pub length: u8,
+	/// Cycles taken by the instruction. For conditional branches, this is when the branch is taken
+	pub cycles: MCycles,
+	pub cycles_not_taken: Option<MCycles>,
+}
+
+impl fmt::Display for DisassembledInstruction {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if self.operands.is_empty() {
+			write!(f, "{}", self.mnemonic)
+		} else {
+			write!(f, "{} {}", self.mnemonic, self.operands.join(","))
+		}
+	}
+}
+
+/// Decodes the instruction at the given address without affecting the rest of the system
+pub fn disassemble(ram: &Ram, address: u16) -> DisassembledInstruction {
+	let opcode = ram.unblocked_read(address);
+	let (instruction, operation, prefix_length) = if opcode == 0o313 {
+		let instruction = ram.unblocked_read(address.wrapping_add(1));
+		(instruction, cb_operation(instruction), 1)
+	} else {
+		(opcode, operation(opcode), 0)
+	};
+
+	let format = operation.format;
+	let immediate_length: u16 = if format.contains("{n16}") || format.contains("{a16}") {
+		2
+	} else if format.contains("{n8}") || format.contains("{a8}") || format.contains("{e8") {
+		1
+	} else {
+		0
+	};
+
+	let length = 1 + prefix_length + immediate_length;
+	let bytes = (0..length).map(|i| ram.unblocked_read(address.wrapping_add(i))).collect::<Vec<u8>>();
+
+	let immediate_start = (1 + prefix_length) as usize;
+	let n8 = bytes.get(immediate_start).copied().unwrap_or(0);
+	let n16 = u16::from_le_bytes([n8, bytes.get(immediate_start + 1).copied().unwrap_or(0)]);
+	let e8 = n8 as i8;
+	let e8_magnitude = e8.unsigned_abs();
+	let e8_sign = if e8 < 0 { "-" } else { "+" };
+
+	let text = format
+		.replace("{r8_middle}", R8_NAMES[((instruction >> 3) & 0b111) as usize])
+		.replace("{r8_last}", R8_NAMES[(instruction & 0b111) as usize])
+		.replace("{r16_stack}", R16_STACK_NAMES[((instruction >> 4) & 0b11) as usize])
+		.replace("{r16}", R16_NAMES[((instruction >> 4) & 0b11) as usize])
+		.replace("{cc}", CC_NAMES[((instruction >> 3) & 0b11) as usize])
+		.replace("{u3}", &((instruction >> 3) & 0b111).to_string())
+		.replace("{rst}", &format!("${:02X}", instruction & 0b0011_1000))
+		.replace("{n8}", &format!("${:02X}", n8))
+		.replace("{a8}", &format!("$FF{:02X}", n8))
+		.replace("{n16}", &format!("${:04X}", n16))
+		.replace("{a16}", &format!("${:04X}", n16))
+		.replace("{e8_target}", &format!("${:04X}", address.wrapping_add(length).wrapping_add_signed(e8 as i16)))
+		.replace("{e8}", &format!("{}${:02X}", e8_sign, e8_magnitude));
+
+	let (mnemonic, operands) = match text.split_once(' ') {
+		Some((mnemonic, operands)) => (mnemonic.to_string(), operands.split(',').map(String::from).collect()),
+		None => (text, vec![]),
+	};
+
+	DisassembledInstruction {
+		address,
+		bytes,
+		mnemonic,
+		operands,
+		length: length as u8,
+		cycles: operation.cycles,
+		cycles_not_taken: operation.cycles_not_taken,
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::ram::TestRamOperations;
+
+	fn disassemble_bytes(bytes: Vec<u8>) -> DisassembledInstruction {
+		let mut ram = Ram::new();
+		ram.test_load(0x0150, bytes);
+		disassemble(&ram, 0x0150)
+	}
+
+	#[test]
+	fn test_disassemble() {
+		let res = disassemble_bytes(vec![0o000]);
+		assert_eq!(res.to_string(), "NOP");
+		assert_eq!(res.length, 1);
+		assert_eq!(res.cycles, 1);
+
+		let res = disassemble_bytes(vec![0o076, 0x12]);
+		assert_eq!(res.mnemonic, "LD");
+		assert_eq!(res.operands, vec!["A", "$12"]);
+		assert_eq!(res.bytes, vec![0o076, 0x12]);
+		assert_eq!(res.length, 2);
+
+		assert_eq!(disassemble_bytes(vec![0o041, 0x34, 0x12]).to_string(), "LD HL,$1234");
+		assert_eq!(disassemble_bytes(vec![0o161]).to_string(), "LD (HL),C");
+		assert_eq!(disassemble_bytes(vec![0o365]).to_string(), "PUSH AF");
+		assert_eq!(disassemble_bytes(vec![0o340, 0x44]).to_string(), "LDH ($FF44),A");
+		assert_eq!(disassemble_bytes(vec![0o357]).to_string(), "RST $28");
+		assert_eq!(disassemble_bytes(vec![0o350, 0xFE]).to_string(), "ADD SP,-$02");
+		assert_eq!(disassemble_bytes(vec![0o370, 0x05]).to_string(), "LD HL,SP+$05");
+		assert_eq!(disassemble_bytes(vec![0o323]).to_string(), "ILLEGAL");
+	}
+
+	#[test]
+	fn test_disassemble_branches() {
+		let res = disassemble_bytes(vec![0o302, 0x00, 0x40]);
+		assert_eq!(res.to_string(), "JP NZ,$4000");
+		assert_eq!(res.cycles, 4);
+		assert_eq!(res.cycles_not_taken, Some(3));
+
+		// Relative jumps show the target address
+		let res = disassemble_bytes(vec![0o070, 0xFE]);
+		assert_eq!(res.to_string(), "JR C,$0150");
+		assert_eq!(res.cycles, 3);
+		assert_eq!(res.cycles_not_taken, Some(2));
+
+		let res = disassemble_bytes(vec![0o311]);
+		assert_eq!(res.cycles_not_taken, None);
+	}
+
+	#[test]
+	fn test_disassemble_cb() {
+		let res = disassemble_bytes(vec![0o313, 0o176]);
+		assert_eq!(res.to_string(), "BIT 7,(HL)");
+		assert_eq!(res.length, 2);
+		assert_eq!(res.cycles, 3);
+
+		assert_eq!(disassemble_bytes(vec![0o313, 0o067]).to_string(), "SWAP A");
+		assert_eq!(disassemble_bytes(vec![0o313, 0o306]).to_string(), "SET 0,(HL)");
+	}
+}
@@ -1,14 +1,55 @@
+use std::array;
 use std::ptr::fn_addr_eq;
+use std::sync::LazyLock;
 use crate::timer::Timer;
+use crate::ppu::PPU;
+use crate::dma::DMA;
+use crate::lcd::Framebuffer;
 use super::register::{Flag, Registers};
 use super::{carry, Ime};
 use super::super::ram::{Ram};
-use super::{CPU, Mode};
+use crate::input::JoypadInput;
+use super::trace::Tracer;
+use super::{BusActivity, CPU, Mode};
+use super::call_stack::{CallFrame, CallStack};
 
 type Instruction = u8;
 pub type MCycles = usize;
 type InstructionHandler = fn(&mut CPU, instruction: &Instruction) -> MCycles;
 
+/// Every opcode decoded once so dispatch is a single index
+static OPERATIONS: LazyLock<[Operation; 256]> =
+	LazyLock::new(|| array::from_fn(|i| CPU::decode_operation(i as Instruction)));
+static CB_OPERATIONS: LazyLock<[Operation; 256]> =
+	LazyLock::new(|| array::from_fn(|i| CPU::decode_cb_operation(i as Instruction)));
+
+/// A decoded opcode along with the metadata needed to disassemble it.
+/// The format is the assembly with placeholders for the operands that depend on the opcode bits or following bytes
+pub(crate) struct Operation {
+	pub(crate) handler: InstructionHandler,
+	pub(crate) format: &'static str,
+	pub(crate) cycles: MCycles,
+	pub(crate) cycles_not_taken: Option<MCycles>,
+}
+
+impl Operation {
+	fn new(handler: InstructionHandler, format: &'static str, cycles: MCycles) -> Self {
+		Operation { handler, format, cycles, cycles_not_taken: None }
+	}
+
+	fn branch(handler: InstructionHandler, format: &'static str, cycles: MCycles, cycles_not_taken: MCycles) -> Self {
+		Operation { handler, format, cycles, cycles_not_taken: Some(cycles_not_taken) }
+	}
+}
+
+pub(crate) fn operation(instruction: Instruction) -> &'static Operation {
+	&OPERATIONS[instruction as usize]
+}
+
+pub(crate) fn cb_operation(instruction: Instruction) -> &'static Operation {
+	&CB_OPERATIONS[instruction as usize]
+}
+
 trait InstructionOps {
 	fn first_u3(&self) -> u8;
 	fn middle_u3(&self) -> u8;
@@ -45,21 +86,30 @@ trait FollowingBytesOps {
 }
 
 impl CPU {
-	// TODO: Stop VRAM or OAM access during certain PPU modes
-
 	pub fn new() -> Self {
 		CPU {
 			registers: Registers::new(),
 			ram: Ram::new(),
 			timer: Timer::new(),
+			ppu: PPU::new(),
+			dma: DMA::new(),
 			ime: Ime::Off,
 			mode: Mode::NormalSpeed,
 			halt_bug_active: false,
+			bus_cycles: 0,
+			tracer: None,
+			bus_log: None,
+			call_stack: None,
 		}
 	}
 
 	pub fn print_cpu(&self) {
-		println!(
+		println!("{}", self.cpu_state());
+	}
+
+	/// The CPU state in the format Gameboy Doctor expects
+	pub fn cpu_state(&self) -> String {
+		format!(
 			"A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
 			self.registers.a,
 			self.registers.f,
@@ -71,40 +121,195 @@ impl CPU {
 			self.registers.l,
 			self.registers.get_sp(),
 			self.registers.pc,
-			self.ram.read(self.registers.pc),
-			self.ram.read(self.registers.pc + 1),
-			self.ram.read(self.registers.pc + 2),
-			self.ram.read(self.registers.pc + 3)
+			self.ram.unblocked_read(self.registers.pc),
+			self.ram.unblocked_read(self.registers.pc.wrapping_add(1)),
+			self.ram.unblocked_read(self.registers.pc.wrapping_add(2)),
+			self.ram.unblocked_read(self.registers.pc.wrapping_add(3))
 		)
 	}
 
+	pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+		self.tracer = tracer;
+	}
+
+	pub fn tracer(&self) -> Option<&Tracer> {
+		self.tracer.as_ref()
+	}
+
+	/// The CPU and LCD are both stopped until a joypad line goes low
+	pub fn stopped(&self) -> bool {
+		self.mode == Mode::VeryLowPower
+	}
+
+	/// The LCD image, once the PPU has finished a frame
+	pub fn take_frame(&mut self) -> Option<Box<Framebuffer>> {
+		self.ppu.take_frame()
+	}
+
+	/// The CPU hung on an illegal opcode. The rest of the system keeps running
+	pub fn is_locked(&self) -> bool {
+		self.mode == Mode::Locked
+	}
+
+	/// Starts recording every M-cycle the CPU spends, or stops recording when disabled
+	pub fn record_bus_activity(&mut self, enabled: bool) {
+		self.bus_log = if enabled { Some(vec![]) } else { None };
+	}
+
+	/// Returns the bus activity recorded so far, leaving recording enabled
+	pub fn take_bus_activity(&mut self) -> Vec<BusActivity> {
+		self.bus_log.as_mut().map(std::mem::take).unwrap_or_default()
+	}
+
+	/// Starts keeping a shadow call stack, or drops it when disabled
+	pub fn track_call_stack(&mut self, enabled: bool) {
+		self.call_stack = if enabled { Some(CallStack::new()) } else { None };
+	}
+
+	pub fn call_stack(&self) -> Option<&CallStack> {
+		self.call_stack.as_ref()
+	}
+
+	pub fn call_stack_mut(&mut self) -> Option<&mut CallStack> {
+		self.call_stack.as_mut()
+	}
+
+	/// Must be called right after the return address was pushed
+	fn push_call_frame(&mut self, caller_pc: u16, return_address: u16, interrupt: bool) {
+		if let Some(call_stack) = &mut self.call_stack {
+			call_stack.push(CallFrame {
+				caller_pc,
+				target: self.registers.pc,
+				rom_bank: self.ram.rom_bank(),
+				interrupt,
+				return_address,
+				stack_pointer: self.registers.get_sp(),
+			});
+		}
+	}
+
+	/// Returns from the call whose return address sits at SP
+	fn return_from_call(&mut self) {
+		let caller_pc = self.registers.pc.wrapping_sub(1);
+		let stack_pointer = self.registers.get_sp();
+		let return_address = self.stack_pop_16();
+		self.registers.pc = return_address;
+
+		if let Some(call_stack) = &mut self.call_stack {
+			call_stack.pop(caller_pc, stack_pointer, return_address);
+		}
+	}
+
+	/// Advances every other component by a single M-cycle
+	fn tick_m_cycle(&mut self) {
+		self.timer.increment_cycle(&mut self.ram, 1);
+		self.dma.tick_transfer(&mut self.ram, 1);
+		self.ppu.tick(1, &mut self.ram);
+		self.bus_cycles += 1;
+
+		if let Some(bus_log) = &mut self.bus_log {
+			bus_log.push(BusActivity::Internal);
+		}
+	}
+
+	/// Replaces the cycle that was just ticked with the memory access that happened during it
+	fn log_bus_access(&mut self, activity: BusActivity) {
+		if let Some(last) = self.bus_log.as_mut().and_then(|bus_log| bus_log.last_mut()) {
+			*last = activity;
+		}
+	}
+
+	/// Internal cycles that an operation didn't spend on memory accesses are ticked at the end
+	fn complete_cycles(&mut self, cycle_count: MCycles) {
+		while self.bus_cycles < cycle_count {
+			self.tick_m_cycle();
+		}
+
+		self.bus_cycles = 0;
+	}
+
+	/// Every memory access takes an M-cycle, which the rest of the system sees before the access happens
+	fn bus_read(&mut self, address: u16) -> u8 {
+		self.tick_m_cycle();
+		let value = self.ram.read(address);
+		self.log_bus_access(BusActivity::Read { address, value });
+
+		value
+	}
+
+	fn bus_write(&mut self, address: u16, value: u8) {
+		self.tick_m_cycle();
+		self.ram.write(address, value);
+		self.log_bus_access(BusActivity::Write { address, value });
+	}
+
 	fn read_byte(&mut self) -> u8 {
-		let data = self.ram.read(self.registers.pc);
+		let data = self.bus_read(self.registers.pc);
 		self.registers.pc += 1;
 
 		data
 	}
 
 	fn read_two_bytes(&mut self) -> u16 {
-		let data = u16::from_le_bytes([self.ram.read(self.registers.pc), self.ram.read(self.registers.pc + 1)]);
+		let data = u16::from_le_bytes([self.bus_read(self.registers.pc), self.bus_read(self.registers.pc + 1)]);
 		self.registers.pc += 2;
 
 		data
 	}
 
 	pub fn execute(&mut self, should_print_cpu_values: bool) -> MCycles {
+		if self.mode == Mode::VeryLowPower {
+			// Everything is stopped (including the timer) until a selected joypad line goes low
+			if !self.ram.joypad_line_low() {
+				return 1;
+			}
+
+			self.mode = Mode::NormalSpeed;
+		}
+
+		if self.mode == Mode::Locked {
+			// Interrupts can't wake a locked CPU, but the PPU and timer keep going
+			self.complete_cycles(1);
+			return 1;
+		}
+
+		if self.mode == Mode::LowPower && self.ram.pending_interrupt().is_none() {
+			// While halted the CPU does nothing but let the rest of the system keep ticking
+			self.complete_cycles(1);
+			return 1;
+		}
+
+		let wake_up_cycles = self.handle_wake_up();
+		self.complete_cycles(wake_up_cycles);
+
+		let interrupt_cycles = self.handle_interrupts();
+		self.complete_cycles(interrupt_cycles);
+
+		// After any interrupt dispatch, so the first instruction of a handler is logged like any other
 		if should_print_cpu_values {
 			self.print_cpu();
 		}
 
-		let cycle_count = self.handle_interrupts();
-		self.timer.increment_cycle(&mut self.ram, cycle_count);
+		if let Some(mut tracer) = self.tracer.take() {
+			tracer.trace(self);
+			self.tracer = Some(tracer);
+		}
 
 		let operation = self.get_operation();
 
 		let cycle_count = self.run_operation(operation);
-		self.timer.increment_cycle(&mut self.ram, cycle_count);
-		cycle_count
+		wake_up_cycles + interrupt_cycles + cycle_count
+	}
+
+	fn handle_wake_up(&mut self) -> MCycles {
+		if self.mode != Mode::LowPower {
+			return 0;
+		}
+
+		// An interrupt is pending (IE & IF). Leaving HALT takes an extra cycle, after which the interrupt is
+		// dispatched if IME is set. Otherwise, execution simply resumes after the HALT instruction
+		self.mode = Mode::NormalSpeed;
+		1
 	}
 
 	fn get_operation(&mut self) -> (Instruction, InstructionHandler) {
@@ -118,135 +323,141 @@ impl CPU {
 
 		if instruction == 0o313 { return self.get_cb_operation(); }
 
-		let function = match instruction {
-			0o000 => CPU::no_op,
-			0o020 => CPU::stop,
-			0o010 => CPU::ld_a16_sp,
-			0o067 => CPU::scf,
-			0o077 => CPU::ccf,
-			0o166 => CPU::halt,
-			i if i.first_u3() == 0 && i.middle_u3() % 2 == 0 && i.last_u3() == 3 => CPU::inc_r16,
-			i if i.first_u3() == 0 && i.middle_u3() % 2 == 1 && i.last_u3() == 3 => CPU::dec_r16,
-			0o064 => CPU::inc_hl,
-			i if i.first_u3() == 0 && i.last_u3() == 4 => CPU::inc_r8,
-			0o065 => CPU::dec_hl,
-			i if i.first_u3() == 0 && i.last_u3() == 5 => CPU::dec_r8,
-			i if i.first_u3() == 0 && i.middle_u3() == 6 && i.last_u3() == 6 => CPU::ld_hl_n8,
-			i if i.first_u3() == 0 && i.last_u3() == 6 => CPU::ld_r8_n8,
-			i if i.first_u3() == 0 && i.middle_u3() % 2 == 0 && i.last_u3() == 1 => CPU::ld_r16_n16,
-			i if i.first_u3() == 0 && i.middle_u3() % 2 == 1 && i.last_u3() == 1 => CPU::add_hl_r16,
-			0o002 => CPU::ld_r16_a,
-			0o012 => CPU::ld_a_r16,
-			0o022 => CPU::ld_r16_a,
-			0o032 => CPU::ld_a_r16,
-			0o042 => CPU::ld_hli_a,
-			0o052 => CPU::ld_a_hli,
-			0o062 => CPU::ld_hld_a,
-			0o072 => CPU::ld_a_hld,
-			0o007 => CPU::rlc_a,
-			0o017 => CPU::rrc_a,
-			0o027 => CPU::rl_a,
-			0o037 => CPU::rr_a,
-			0o047 => CPU::daa,
-			0o057 => CPU::cpl,
-			0o030 => CPU::jr_n16,
-			0o040 => CPU::jr_cc_n16,
-			0o050 => CPU::jr_cc_n16,
-			0o060 => CPU::jr_cc_n16,
-			0o070 => CPU::jr_cc_n16,
-			i if i.first_u3() == 0 && i.last_u3() == 2 => CPU::ld_r16_a,
-			i if i.first_u3() == 1 && i.middle_u3() == 6 => CPU::ld_hl_r8,
-			i if i.first_u3() == 1 && i.last_u3() == 6 => CPU::ld_r8_hl,
-			i if i.first_u3() == 1 => CPU::ld_r8_r8,
-			0o206 => CPU::add_a_hl,
-			0o200..=0o207 => CPU::add_a_r8,
-			0o216 => CPU::addc_a_hl,
-			0o210..=0o217 => CPU::addc_a_r8,
-			0o226 => CPU::sub_a_hl,
-			0o220..=0o227 => CPU::sub_a_r8,
-			0o236 => CPU::subc_a_hl,
-			0o230..=0o237 => CPU::subc_a_r8,
-			0o246 => CPU::and_a_hl,
-			0o240..=0o247 => CPU::and_a_r8,
-			0o256 => CPU::xor_a_hl,
-			0o250..=0o257 => CPU::xor_a_r8,
-			0o266 => CPU::or_a_hl,
-			0o260..=0o267 => CPU::or_a_r8,
-			0o276 => CPU::cp_a_hl,
-			0o270..=0o277 => CPU::cp_a_r8,
-			0o303 => CPU::jp_n16,
-			0o304 => CPU::call_cc_n16,
-			0o314 => CPU::call_cc_n16,
-			0o324 => CPU::call_cc_n16,
-			0o334 => CPU::call_cc_n16,
-			0o300 => CPU::ret_cc,
-			0o310 => CPU::ret_cc,
-			0o320 => CPU::ret_cc,
-			0o330 => CPU::ret_cc,
-			0o311 => CPU::ret,
-			0o331 => CPU::reti,
-			0o307 => CPU::rst,
-			0o317 => CPU::rst,
-			0o327 => CPU::rst,
-			0o337 => CPU::rst,
-			0o347 => CPU::rst,
-			0o357 => CPU::rst,
-			0o367 => CPU::rst,
-			0o377 => CPU::rst,
-			0o306 => CPU::add_a_n8,
-			0o316 => CPU::addc_a_n8,
-			0o326 => CPU::sub_a_n8,
-			0o336 => CPU::subc_a_n8,
-			0o346 => CPU::and_a_n8,
-			0o301 => CPU::pop_r16,
-			0o321 => CPU::pop_r16,
-			0o341 => CPU::pop_r16,
-			0o350 => CPU::add_sp_e8,
-			0o351 => CPU::jp_hl,
-			0o361 => CPU::pop_r16,
-			0o363 => CPU::di,
-			0o370 => CPU::ld_hl_sp_plus_e8,
-			0o371 => CPU::ld_sp_hl,
-			0o373 => CPU::ei,
-			0o305 => CPU::push_r16,
-			0o315 => CPU::call_n16,
-			0o325 => CPU::push_r16,
-			0o345 => CPU::push_r16,
-			0o365 => CPU::push_r16,
-			0o302 => CPU::jp_cc_n16,
-			0o312 => CPU::jp_cc_n16,
-			0o322 => CPU::jp_cc_n16,
-			0o332 => CPU::jp_cc_n16,
-			0o356 => CPU::xor_a_n8,
-			0o366 => CPU::or_a_n8,
-			0o376 => CPU::cp_a_n8,
-			0o340 => CPU::ldh_n16_a,
-			0o342 => CPU::ldh_c_a,
-			0o352 => CPU::ld_n16_a,
-			0o360 => CPU::ldh_a_a16,
-			0o362 => CPU::ldh_a_c,
-			0o372 => CPU::ld_a_n16,
+		(instruction, OPERATIONS[instruction as usize].handler)
+	}
+
+	/// Maps an opcode to its handler and metadata. Only used to build the `OPERATIONS` table
+	fn decode_operation(instruction: Instruction) -> Operation {
+		match instruction {
+			0o000 => Operation::new(CPU::no_op, "NOP", 1),
+			0o020 => Operation::new(CPU::stop, "STOP {n8}", 1),
+			0o010 => Operation::new(CPU::ld_a16_sp, "LD ({a16}),SP", 5),
+			0o067 => Operation::new(CPU::scf, "SCF", 1),
+			0o077 => Operation::new(CPU::ccf, "CCF", 1),
+			0o166 => Operation::new(CPU::halt, "HALT", 1),
+			i if i.first_u3() == 0 && i.middle_u3() % 2 == 0 && i.last_u3() == 3 => Operation::new(CPU::inc_r16, "INC {r16}", 2),
+			i if i.first_u3() == 0 && i.middle_u3() % 2 == 1 && i.last_u3() == 3 => Operation::new(CPU::dec_r16, "DEC {r16}", 2),
+			0o064 => Operation::new(CPU::inc_hl, "INC (HL)", 3),
+			i if i.first_u3() == 0 && i.last_u3() == 4 => Operation::new(CPU::inc_r8, "INC {r8_middle}", 1),
+			0o065 => Operation::new(CPU::dec_hl, "DEC (HL)", 3),
+			i if i.first_u3() == 0 && i.last_u3() == 5 => Operation::new(CPU::dec_r8, "DEC {r8_middle}", 1),
+			i if i.first_u3() == 0 && i.middle_u3() == 6 && i.last_u3() == 6 => Operation::new(CPU::ld_hl_n8, "LD (HL),{n8}", 3),
+			i if i.first_u3() == 0 && i.last_u3() == 6 => Operation::new(CPU::ld_r8_n8, "LD {r8_middle},{n8}", 2),
+			i if i.first_u3() == 0 && i.middle_u3() % 2 == 0 && i.last_u3() == 1 => Operation::new(CPU::ld_r16_n16, "LD {r16},{n16}", 3),
+			i if i.first_u3() == 0 && i.middle_u3() % 2 == 1 && i.last_u3() == 1 => Operation::new(CPU::add_hl_r16, "ADD HL,{r16}", 2),
+			0o002 => Operation::new(CPU::ld_r16_a, "LD ({r16}),A", 2),
+			0o012 => Operation::new(CPU::ld_a_r16, "LD A,({r16})", 2),
+			0o022 => Operation::new(CPU::ld_r16_a, "LD ({r16}),A", 2),
+			0o032 => Operation::new(CPU::ld_a_r16, "LD A,({r16})", 2),
+			0o042 => Operation::new(CPU::ld_hli_a, "LD (HL+),A", 2),
+			0o052 => Operation::new(CPU::ld_a_hli, "LD A,(HL+)", 2),
+			0o062 => Operation::new(CPU::ld_hld_a, "LD (HL-),A", 2),
+			0o072 => Operation::new(CPU::ld_a_hld, "LD A,(HL-)", 2),
+			0o007 => Operation::new(CPU::rlc_a, "RLCA", 1),
+			0o017 => Operation::new(CPU::rrc_a, "RRCA", 1),
+			0o027 => Operation::new(CPU::rl_a, "RLA", 1),
+			0o037 => Operation::new(CPU::rr_a, "RRA", 1),
+			0o047 => Operation::new(CPU::daa, "DAA", 1),
+			0o057 => Operation::new(CPU::cpl, "CPL", 1),
+			0o030 => Operation::new(CPU::jr_n16, "JR {e8_target}", 3),
+			0o040 => Operation::branch(CPU::jr_cc_n16, "JR {cc},{e8_target}", 3, 2),
+			0o050 => Operation::branch(CPU::jr_cc_n16, "JR {cc},{e8_target}", 3, 2),
+			0o060 => Operation::branch(CPU::jr_cc_n16, "JR {cc},{e8_target}", 3, 2),
+			0o070 => Operation::branch(CPU::jr_cc_n16, "JR {cc},{e8_target}", 3, 2),
+			i if i.first_u3() == 0 && i.last_u3() == 2 => Operation::new(CPU::ld_r16_a, "LD ({r16}),A", 2),
+			i if i.first_u3() == 1 && i.middle_u3() == 6 => Operation::new(CPU::ld_hl_r8, "LD (HL),{r8_last}", 2),
+			i if i.first_u3() == 1 && i.last_u3() == 6 => Operation::new(CPU::ld_r8_hl, "LD {r8_middle},(HL)", 2),
+			i if i.first_u3() == 1 => Operation::new(CPU::ld_r8_r8, "LD {r8_middle},{r8_last}", 1),
+			0o206 => Operation::new(CPU::add_a_hl, "ADD A,(HL)", 2),
+			0o200..=0o207 => Operation::new(CPU::add_a_r8, "ADD A,{r8_last}", 1),
+			0o216 => Operation::new(CPU::addc_a_hl, "ADC A,(HL)", 2),
+			0o210..=0o217 => Operation::new(CPU::addc_a_r8, "ADC A,{r8_last}", 1),
+			0o226 => Operation::new(CPU::sub_a_hl, "SUB A,(HL)", 2),
+			0o220..=0o227 => Operation::new(CPU::sub_a_r8, "SUB A,{r8_last}", 1),
+			0o236 => Operation::new(CPU::subc_a_hl, "SBC A,(HL)", 2),
+			0o230..=0o237 => Operation::new(CPU::subc_a_r8, "SBC A,{r8_last}", 1),
+			0o246 => Operation::new(CPU::and_a_hl, "AND A,(HL)", 2),
+			0o240..=0o247 => Operation::new(CPU::and_a_r8, "AND A,{r8_last}", 1),
+			0o256 => Operation::new(CPU::xor_a_hl, "XOR A,(HL)", 2),
+			0o250..=0o257 => Operation::new(CPU::xor_a_r8, "XOR A,{r8_last}", 1),
+			0o266 => Operation::new(CPU::or_a_hl, "OR A,(HL)", 2),
+			0o260..=0o267 => Operation::new(CPU::or_a_r8, "OR A,{r8_last}", 1),
+			0o276 => Operation::new(CPU::cp_a_hl, "CP A,(HL)", 2),
+			0o270..=0o277 => Operation::new(CPU::cp_a_r8, "CP A,{r8_last}", 1),
+			0o303 => Operation::new(CPU::jp_n16, "JP {a16}", 4),
+			0o304 => Operation::branch(CPU::call_cc_n16, "CALL {cc},{a16}", 6, 3),
+			0o314 => Operation::branch(CPU::call_cc_n16, "CALL {cc},{a16}", 6, 3),
+			0o324 => Operation::branch(CPU::call_cc_n16, "CALL {cc},{a16}", 6, 3),
+			0o334 => Operation::branch(CPU::call_cc_n16, "CALL {cc},{a16}", 6, 3),
+			0o300 => Operation::branch(CPU::ret_cc, "RET {cc}", 5, 2),
+			0o310 => Operation::branch(CPU::ret_cc, "RET {cc}", 5, 2),
+			0o320 => Operation::branch(CPU::ret_cc, "RET {cc}", 5, 2),
+			0o330 => Operation::branch(CPU::ret_cc, "RET {cc}", 5, 2),
+			0o311 => Operation::new(CPU::ret, "RET", 4),
+			0o331 => Operation::new(CPU::reti, "RETI", 4),
+			0o307 => Operation::new(CPU::rst, "RST {rst}", 4),
+			0o317 => Operation::new(CPU::rst, "RST {rst}", 4),
+			0o327 => Operation::new(CPU::rst, "RST {rst}", 4),
+			0o337 => Operation::new(CPU::rst, "RST {rst}", 4),
+			0o347 => Operation::new(CPU::rst, "RST {rst}", 4),
+			0o357 => Operation::new(CPU::rst, "RST {rst}", 4),
+			0o367 => Operation::new(CPU::rst, "RST {rst}", 4),
+			0o377 => Operation::new(CPU::rst, "RST {rst}", 4),
+			0o306 => Operation::new(CPU::add_a_n8, "ADD A,{n8}", 2),
+			0o316 => Operation::new(CPU::addc_a_n8, "ADC A,{n8}", 2),
+			0o326 => Operation::new(CPU::sub_a_n8, "SUB A,{n8}", 2),
+			0o336 => Operation::new(CPU::subc_a_n8, "SBC A,{n8}", 2),
+			0o346 => Operation::new(CPU::and_a_n8, "AND A,{n8}", 2),
+			0o301 => Operation::new(CPU::pop_r16, "POP {r16_stack}", 3),
+			0o321 => Operation::new(CPU::pop_r16, "POP {r16_stack}", 3),
+			0o341 => Operation::new(CPU::pop_r16, "POP {r16_stack}", 3),
+			0o350 => Operation::new(CPU::add_sp_e8, "ADD SP,{e8}", 4),
+			0o351 => Operation::new(CPU::jp_hl, "JP HL", 1),
+			0o361 => Operation::new(CPU::pop_r16, "POP {r16_stack}", 3),
+			0o363 => Operation::new(CPU::di, "DI", 1),
+			0o370 => Operation::new(CPU::ld_hl_sp_plus_e8, "LD HL,SP{e8}", 3),
+			0o371 => Operation::new(CPU::ld_sp_hl, "LD SP,HL", 2),
+			0o373 => Operation::new(CPU::ei, "EI", 1),
+			0o305 => Operation::new(CPU::push_r16, "PUSH {r16_stack}", 4),
+			0o315 => Operation::new(CPU::call_n16, "CALL {a16}", 6),
+			0o325 => Operation::new(CPU::push_r16, "PUSH {r16_stack}", 4),
+			0o345 => Operation::new(CPU::push_r16, "PUSH {r16_stack}", 4),
+			0o365 => Operation::new(CPU::push_r16, "PUSH {r16_stack}", 4),
+			0o302 => Operation::branch(CPU::jp_cc_n16, "JP {cc},{a16}", 4, 3),
+			0o312 => Operation::branch(CPU::jp_cc_n16, "JP {cc},{a16}", 4, 3),
+			0o322 => Operation::branch(CPU::jp_cc_n16, "JP {cc},{a16}", 4, 3),
+			0o332 => Operation::branch(CPU::jp_cc_n16, "JP {cc},{a16}", 4, 3),
+			0o356 => Operation::new(CPU::xor_a_n8, "XOR A,{n8}", 2),
+			0o366 => Operation::new(CPU::or_a_n8, "OR A,{n8}", 2),
+			0o376 => Operation::new(CPU::cp_a_n8, "CP A,{n8}", 2),
+			0o340 => Operation::new(CPU::ldh_n16_a, "LDH ({a8}),A", 3),
+			0o342 => Operation::new(CPU::ldh_c_a, "LDH (C),A", 2),
+			0o352 => Operation::new(CPU::ld_n16_a, "LD ({a16}),A", 4),
+			0o360 => Operation::new(CPU::ldh_a_a16, "LDH A,({a8})", 3),
+			0o362 => Operation::new(CPU::ldh_a_c, "LDH A,(C)", 2),
+			0o372 => Operation::new(CPU::ld_a_n16, "LD A,({a16})", 4),
 			// These are the unmapped instructions
-			0o323 => CPU::no_op,
-			0o333 => CPU::no_op,
-			0o343 => CPU::no_op,
-			0o353 => CPU::no_op,
-			0o344 => CPU::no_op,
-			0o354 => CPU::no_op,
-			0o364 => CPU::no_op,
-			0o374 => CPU::no_op,
-			0o335 => CPU::no_op,
-			0o355 => CPU::no_op,
-			0o375 => CPU::no_op,
+			0o323 => Operation::new(CPU::illegal, "ILLEGAL", 1),
+			0o333 => Operation::new(CPU::illegal, "ILLEGAL", 1),
+			0o343 => Operation::new(CPU::illegal, "ILLEGAL", 1),
+			0o353 => Operation::new(CPU::illegal, "ILLEGAL", 1),
+			0o344 => Operation::new(CPU::illegal, "ILLEGAL", 1),
+			0o354 => Operation::new(CPU::illegal, "ILLEGAL", 1),
+			0o364 => Operation::new(CPU::illegal, "ILLEGAL", 1),
+			0o374 => Operation::new(CPU::illegal, "ILLEGAL", 1),
+			0o335 => Operation::new(CPU::illegal, "ILLEGAL", 1),
+			0o355 => Operation::new(CPU::illegal, "ILLEGAL", 1),
+			0o375 => Operation::new(CPU::illegal, "ILLEGAL", 1),
+			// The CB prefix is resolved before the table lookup
+			0o313 => Operation::new(CPU::prefix_cb, "PREFIX", 1),
 			_ => panic!("Unhandled instruction: {instruction:3o}"),
-		};
-
-		(instruction, function)
+		}
 	}
 
 	fn run_operation(&mut self, data: (Instruction, InstructionHandler)) -> MCycles {
 		let (instruction, op) = data;
 		let cycles = op(self, &instruction);
+		self.complete_cycles(cycles);
 
 		// ime flag setting has a one instruction delay
 		if !fn_addr_eq(op, CPU::ei as InstructionHandler) && self.ime == Ime::ToSet {
@@ -259,39 +470,51 @@ impl CPU {
 	fn get_cb_operation(&mut self) -> (Instruction, InstructionHandler) {
 		let instruction = self.read_byte();
 
-		let op = match instruction {
-			i if i.first_u3() == 0 && i.middle_u3() == 0 && i.last_u3() == 6 => CPU::rlc_hl,
-			i if i.first_u3() == 0 && i.middle_u3() == 0 => CPU::rlc_r8,
-			i if i.first_u3() == 0 && i.middle_u3() == 1 && i.last_u3() == 6 => CPU::rrc_hl,
-			i if i.first_u3() == 0 && i.middle_u3() == 1 => CPU::rrc_r8,
-			i if i.first_u3() == 0 && i.middle_u3() == 2 && i.last_u3() == 6 => CPU::rl_hl,
-			i if i.first_u3() == 0 && i.middle_u3() == 2 => CPU::rl_r8,
-			i if i.first_u3() == 0 && i.middle_u3() == 3 && i.last_u3() == 6 => CPU::rr_hl,
-			i if i.first_u3() == 0 && i.middle_u3() == 3 => CPU::rr_r8,
-			i if i.first_u3() == 0 && i.middle_u3() == 4 && i.last_u3() == 6 => CPU::sla_hl,
-			i if i.first_u3() == 0 && i.middle_u3() == 4 => CPU::sla_r8,
-			i if i.first_u3() == 0 && i.middle_u3() == 5 && i.last_u3() == 6 => CPU::sra_hl,
-			i if i.first_u3() == 0 && i.middle_u3() == 5 => CPU::sra_r8,
-			i if i.first_u3() == 0 && i.middle_u3() == 6 && i.last_u3() == 6 => CPU::swap_hl,
-			i if i.first_u3() == 0 && i.middle_u3() == 6 => CPU::swap_r8,
-			i if i.first_u3() == 0 && i.middle_u3() == 7 && i.last_u3() == 6 => CPU::srl_hl,
-			i if i.first_u3() == 0 && i.middle_u3() == 7 => CPU::srl_r8,
-			i if i.first_u3() == 1 && i.last_u3() == 6 => CPU::bit_u3_hl,
-			i if i.first_u3() == 1 => CPU::bit_u3_r8,
-			i if i.first_u3() == 2 && i.last_u3() == 6 => CPU::res_u3_hl,
-			i if i.first_u3() == 2 => CPU::res_u3_r8,
-			i if i.first_u3() == 3 && i.last_u3() == 6 => CPU::set_u3_hl,
-			i if i.first_u3() == 3 => CPU::set_u3_r8,
+		(instruction, CB_OPERATIONS[instruction as usize].handler)
+	}
+
+	/// Maps a CB prefixed opcode to its handler and metadata. Only used to build the `CB_OPERATIONS` table
+	fn decode_cb_operation(instruction: Instruction) -> Operation {
+		match instruction {
+			i if i.first_u3() == 0 && i.middle_u3() == 0 && i.last_u3() == 6 => Operation::new(CPU::rlc_hl, "RLC {r8_last}", 4),
+			i if i.first_u3() == 0 && i.middle_u3() == 0 => Operation::new(CPU::rlc_r8, "RLC {r8_last}", 2),
+			i if i.first_u3() == 0 && i.middle_u3() == 1 && i.last_u3() == 6 => Operation::new(CPU::rrc_hl, "RRC {r8_last}", 4),
+			i if i.first_u3() == 0 && i.middle_u3() == 1 => Operation::new(CPU::rrc_r8, "RRC {r8_last}", 2),
+			i if i.first_u3() == 0 && i.middle_u3() == 2 && i.last_u3() == 6 => Operation::new(CPU::rl_hl, "RL {r8_last}", 4),
+			i if i.first_u3() == 0 && i.middle_u3() == 2 => Operation::new(CPU::rl_r8, "RL {r8_last}", 2),
+			i if i.first_u3() == 0 && i.middle_u3() == 3 && i.last_u3() == 6 => Operation::new(CPU::rr_hl, "RR {r8_last}", 4),
+			i if i.first_u3() == 0 && i.middle_u3() == 3 => Operation::new(CPU::rr_r8, "RR {r8_last}", 2),
+			i if i.first_u3() == 0 && i.middle_u3() == 4 && i.last_u3() == 6 => Operation::new(CPU::sla_hl, "SLA {r8_last}", 4),
+			i if i.first_u3() == 0 && i.middle_u3() == 4 => Operation::new(CPU::sla_r8, "SLA {r8_last}", 2),
+			i if i.first_u3() == 0 && i.middle_u3() == 5 && i.last_u3() == 6 => Operation::new(CPU::sra_hl, "SRA {r8_last}", 4),
+			i if i.first_u3() == 0 && i.middle_u3() == 5 => Operation::new(CPU::sra_r8, "SRA {r8_last}", 2),
+			i if i.first_u3() == 0 && i.middle_u3() == 6 && i.last_u3() == 6 => Operation::new(CPU::swap_hl, "SWAP {r8_last}", 4),
+			i if i.first_u3() == 0 && i.middle_u3() == 6 => Operation::new(CPU::swap_r8, "SWAP {r8_last}", 2),
+			i if i.first_u3() == 0 && i.middle_u3() == 7 && i.last_u3() == 6 => Operation::new(CPU::srl_hl, "SRL {r8_last}", 4),
+			i if i.first_u3() == 0 && i.middle_u3() == 7 => Operation::new(CPU::srl_r8, "SRL {r8_last}", 2),
+			i if i.first_u3() == 1 && i.last_u3() == 6 => Operation::new(CPU::bit_u3_hl, "BIT {u3},{r8_last}", 3),
+			i if i.first_u3() == 1 => Operation::new(CPU::bit_u3_r8, "BIT {u3},{r8_last}", 2),
+			i if i.first_u3() == 2 && i.last_u3() == 6 => Operation::new(CPU::res_u3_hl, "RES {u3},{r8_last}", 4),
+			i if i.first_u3() == 2 => Operation::new(CPU::res_u3_r8, "RES {u3},{r8_last}", 2),
+			i if i.first_u3() == 3 && i.last_u3() == 6 => Operation::new(CPU::set_u3_hl, "SET {u3},{r8_last}", 4),
+			i if i.first_u3() == 3 => Operation::new(CPU::set_u3_r8, "SET {u3},{r8_last}", 2),
 			_ => panic!("Unhandled CB instruction: {instruction}"),
-		};
-
-		(instruction, op)
+		}
 	}
 
 	fn no_op(&mut self, _: &Instruction) -> MCycles {
 		1
 	}
 
+	fn illegal(&mut self, _: &Instruction) -> MCycles {
+		self.mode = Mode::Locked;
+		1
+	}
+
+	fn prefix_cb(&mut self, _: &Instruction) -> MCycles {
+		unreachable!("CB prefixed instructions are dispatched through CB_OPERATIONS")
+	}
+
 	fn add_a_r8(&mut self, instruction: &Instruction) -> MCycles {
 		let src = *self.registers.get_r8(instruction.last_u3());
 		self.alu_add_a(src, false);
@@ -300,7 +523,7 @@ impl CPU {
 	}
 
 	fn add_a_hl(&mut self, _: &Instruction) -> MCycles {
-		let src = self.ram.read(self.registers.get_hl());
+		let src = self.bus_read(self.registers.get_hl());
 		self.alu_add_a(src, false);
 
 		2
@@ -321,7 +544,7 @@ impl CPU {
 	}
 
 	fn addc_a_hl(&mut self, _: &Instruction) -> MCycles {
-		let src = self.ram.read(self.registers.get_hl());
+		let src = self.bus_read(self.registers.get_hl());
 		self.alu_add_a(src, true);
 
 		2
@@ -373,7 +596,7 @@ impl CPU {
 	}
 
 	fn sub_a_hl(&mut self, _: &Instruction) -> MCycles {
-		let src = self.ram.read(self.registers.get_hl());
+		let src = self.bus_read(self.registers.get_hl());
 		self.alu_sub_a(src, false);
 
 		2
@@ -394,7 +617,7 @@ impl CPU {
 	}
 
 	fn subc_a_hl(&mut self, _: &Instruction) -> MCycles {
-		let src = self.ram.read(self.registers.get_hl());
+		let src = self.bus_read(self.registers.get_hl());
 		self.alu_sub_a(src, true);
 
 		2
@@ -440,7 +663,7 @@ impl CPU {
 	}
 
 	fn and_a_hl(&mut self, _: &Instruction) -> MCycles {
-		let src = self.ram.read(self.registers.get_hl());
+		let src = self.bus_read(self.registers.get_hl());
 		self.alu_and_a(src);
 
 		2
@@ -470,7 +693,7 @@ impl CPU {
 	}
 
 	fn xor_a_hl(&mut self, _: &Instruction) -> MCycles {
-		let src = self.ram.read(self.registers.get_hl());
+		let src = self.bus_read(self.registers.get_hl());
 		self.alu_xor_a(src);
 
 		2
@@ -500,7 +723,7 @@ impl CPU {
 	}
 
 	fn or_a_hl(&mut self, _: &Instruction) -> MCycles {
-		let src = self.ram.read(self.registers.get_hl());
+		let src = self.bus_read(self.registers.get_hl());
 		self.alu_or_a(src);
 
 		2
@@ -530,7 +753,7 @@ impl CPU {
 	}
 
 	fn cp_a_hl(&mut self, _: &Instruction) -> MCycles {
-		let value = self.ram.read(self.registers.get_hl());
+		let value = self.bus_read(self.registers.get_hl());
 		self.alu_cp_a(value);
 
 		2
@@ -567,10 +790,10 @@ impl CPU {
 
 	fn inc_hl(&mut self, _: &Instruction) -> MCycles {
 		let location = self.registers.get_hl();
-		let prev = self.ram.read(location);
+		let prev = self.bus_read(location);
 
 		let (res, _) = prev.overflowing_add(1);
-		self.ram.write(location, res);
+		self.bus_write(location, res);
 
 		self.registers.set_flag(Flag::Zero, res == 0);
 		self.registers.set_flag(Flag::Subtraction, false);
@@ -603,10 +826,10 @@ impl CPU {
 
 	fn dec_hl(&mut self, _: &Instruction) -> MCycles {
 		let location = self.registers.get_hl();
-		let prev = self.ram.read(location);
+		let prev = self.bus_read(location);
 
 		let (res, _) = prev.overflowing_sub(1);
-		self.ram.write(location, res);
+		self.bus_write(location, res);
 
 		self.registers.set_flag(Flag::Zero, res == 0);
 		self.registers.set_flag(Flag::Subtraction, true);
@@ -672,7 +895,7 @@ impl CPU {
 	fn ld_hl_r8(&mut self, instruction: &Instruction) -> MCycles {
 		let value_to_load = *self.registers.get_r8(instruction.last_u3());
 		let location = self.registers.get_hl();
-		self.ram.write(location, value_to_load);
+		self.bus_write(location, value_to_load);
 
 		2
 	}
@@ -680,7 +903,7 @@ impl CPU {
 	fn ld_hl_n8(&mut self, _: &Instruction) -> MCycles {
 		let value_to_load = self.read_byte();
 		let location = self.registers.get_hl();
-		self.ram.write(location, value_to_load);
+		self.bus_write(location, value_to_load);
 
 		3
 	}
@@ -694,8 +917,8 @@ impl CPU {
 
 	fn ld_r8_hl(&mut self, instruction: &Instruction) -> MCycles {
 		let location = self.registers.get_hl();
-		let register = self.registers.get_r8(instruction.middle_u3());
-		*register = self.ram.read(location);
+		let value = self.bus_read(location);
+		*self.registers.get_r8(instruction.middle_u3()) = value;
 
 		2
 	}
@@ -703,7 +926,7 @@ impl CPU {
 	fn ld_r16_a(&mut self, instruction: &Instruction) -> MCycles {
 		let value_to_load = self.registers.a;
 		let location = self.registers.get_r16(instruction.interleaved_r16(true));
-		self.ram.write(location, value_to_load);
+		self.bus_write(location, value_to_load);
 
 		2
 	}
@@ -711,7 +934,7 @@ impl CPU {
 	fn ld_n16_a(&mut self, _: &Instruction) -> MCycles {
 		let value_to_load = self.registers.a;
 		let location = self.read_two_bytes();
-		self.ram.write(location, value_to_load);
+		self.bus_write(location, value_to_load);
 
 		4
 	}
@@ -719,8 +942,8 @@ impl CPU {
 	fn ld_a16_sp(&mut self, _: &Instruction) -> MCycles {
 		let [lo, hi] = self.registers.get_sp().to_le_bytes();
 		let location = self.read_two_bytes();
-		self.ram.write(location, lo);
-		self.ram.write(location + 1, hi);
+		self.bus_write(location, lo);
+		self.bus_write(location + 1, hi);
 
 		5
 	}
@@ -729,7 +952,7 @@ impl CPU {
 		let location = self.read_byte();
 		let location = 0xFF00 + location as u16;
 		let value_to_load = self.registers.a;
-		self.ram.write(location, value_to_load);
+		self.bus_write(location, value_to_load);
 
 		3
 	}
@@ -737,21 +960,21 @@ impl CPU {
 	fn ldh_c_a(&mut self, _: &Instruction) -> MCycles {
 		let c = self.registers.c as u16;
 		let location = 0xFF00 + c;
-		self.ram.write(location, self.registers.a);
+		self.bus_write(location, self.registers.a);
 
 		2
 	}
 
 	fn ld_a_r16(&mut self, instruction: &Instruction) -> MCycles {
 		let location = self.registers.get_r16(instruction.interleaved_r16(false));
-		self.registers.a = self.ram.read(location);
+		self.registers.a = self.bus_read(location);
 
 		2
 	}
 
 	fn ld_a_n16(&mut self, _: &Instruction) -> MCycles {
 		let location = self.read_two_bytes();
-		self.registers.a = self.ram.read(location);
+		self.registers.a = self.bus_read(location);
 
 		4
 	}
@@ -759,7 +982,7 @@ impl CPU {
 	fn ldh_a_a16(&mut self, _: &Instruction) -> MCycles {
 		let location = self.read_byte();
 		let location = 0xFF00 + location as u16;
-		self.registers.a = self.ram.read(location);
+		self.registers.a = self.bus_read(location);
 
 		3
 	}
@@ -768,7 +991,7 @@ impl CPU {
 		let c = self.registers.c as u16;
 		let location = 0xFF00 + c;
 
-		self.registers.a = self.ram.read(location);
+		self.registers.a = self.bus_read(location);
 
 		2
 	}
@@ -828,7 +1051,7 @@ impl CPU {
 	fn bit_u3_hl(&mut self, instruction: &Instruction) -> MCycles {
 		let bit_index = instruction.middle_u3();
 		let location = self.registers.get_hl();
-		let value = self.ram.read(location);
+		let value = self.bus_read(location);
 		self.alu_bit_u3(bit_index, value);
 
 		3
@@ -853,9 +1076,9 @@ impl CPU {
 	fn res_u3_hl(&mut self, instruction: &Instruction) -> MCycles {
 		let bit_mask = 1 << instruction.middle_u3();
 		let location = self.registers.get_hl();
-		let data = self.ram.read(location);
+		let data = self.bus_read(location);
 
-		self.ram.write(location, data & !bit_mask);
+		self.bus_write(location, data & !bit_mask);
 		4
 	}
 
@@ -870,9 +1093,9 @@ impl CPU {
 	fn set_u3_hl(&mut self, instruction: &Instruction) -> MCycles {
 		let bit_mask = 1 << instruction.middle_u3();
 		let location = self.registers.get_hl();
-		let data = self.ram.read(location);
+		let data = self.bus_read(location);
 
-		self.ram.write(location, data | bit_mask);
+		self.bus_write(location, data | bit_mask);
 		4
 	}
 
@@ -885,9 +1108,9 @@ impl CPU {
 	}
 
 	fn rl_hl(&mut self, _: &Instruction) -> MCycles {
-		let value = self.ram.read(self.registers.get_hl());
+		let value = self.bus_read(self.registers.get_hl());
 		let res = self.alu_rotate(value, Direction::LEFT, RotateType::RotateThroughCarry);
-		self.ram.write(self.registers.get_hl(), res);
+		self.bus_write(self.registers.get_hl(), res);
 
 		4
 	}
@@ -911,9 +1134,9 @@ impl CPU {
 	}
 
 	fn rlc_hl(&mut self, _: &Instruction) -> MCycles {
-		let value = self.ram.read(self.registers.get_hl());
+		let value = self.bus_read(self.registers.get_hl());
 		let res = self.alu_rotate(value, Direction::LEFT, RotateType::RotateWithoutCarry);
-		self.ram.write(self.registers.get_hl(), res);
+		self.bus_write(self.registers.get_hl(), res);
 
 		4
 	}
@@ -937,9 +1160,9 @@ impl CPU {
 	}
 
 	fn sla_hl(&mut self, _: &Instruction) -> MCycles {
-		let value = self.ram.read(self.registers.get_hl());
+		let value = self.bus_read(self.registers.get_hl());
 		let res = self.alu_rotate(value, Direction::LEFT, RotateType::Shift);
-		self.ram.write(self.registers.get_hl(), res);
+		self.bus_write(self.registers.get_hl(), res);
 
 		4
 	}
@@ -953,9 +1176,9 @@ impl CPU {
 	}
 
 	fn rr_hl(&mut self, _: &Instruction) -> MCycles {
-		let value = self.ram.read(self.registers.get_hl());
+		let value = self.bus_read(self.registers.get_hl());
 		let res = self.alu_rotate(value, Direction::RIGHT, RotateType::RotateThroughCarry);
-		self.ram.write(self.registers.get_hl(), res);
+		self.bus_write(self.registers.get_hl(), res);
 
 		4
 	}
@@ -979,9 +1202,9 @@ impl CPU {
 	}
 
 	fn rrc_hl(&mut self, _: &Instruction) -> MCycles {
-		let value = self.ram.read(self.registers.get_hl());
+		let value = self.bus_read(self.registers.get_hl());
 		let res = self.alu_rotate(value, Direction::RIGHT, RotateType::RotateWithoutCarry);
-		self.ram.write(self.registers.get_hl(), res);
+		self.bus_write(self.registers.get_hl(), res);
 
 		4
 	}
@@ -1009,13 +1232,13 @@ impl CPU {
 	}
 
 	fn sra_hl(&mut self, _: &Instruction) -> MCycles {
-		let value = self.ram.read(self.registers.get_hl());
+		let value = self.bus_read(self.registers.get_hl());
 		let mut res = self.alu_rotate(value, Direction::RIGHT, RotateType::Shift);
 
 		let bit_7_mask = value & 0b1000_0000;
 		res = if bit_7_mask > 0 { res | bit_7_mask } else { res & !bit_7_mask };
 
-		self.ram.write(self.registers.get_hl(), res);
+		self.bus_write(self.registers.get_hl(), res);
 
 		4
 	}
@@ -1029,9 +1252,9 @@ impl CPU {
 	}
 
 	fn srl_hl(&mut self, _: &Instruction) -> MCycles {
-		let value = self.ram.read(self.registers.get_hl());
+		let value = self.bus_read(self.registers.get_hl());
 		let res = self.alu_rotate(value, Direction::RIGHT, RotateType::Shift);
-		self.ram.write(self.registers.get_hl(), res);
+		self.bus_write(self.registers.get_hl(), res);
 
 		4
 	}
@@ -1066,9 +1289,9 @@ impl CPU {
 	}
 
 	fn swap_hl(&mut self, _: &Instruction) -> MCycles {
-		let value = self.ram.read(self.registers.get_hl());
+		let value = self.bus_read(self.registers.get_hl());
 		let res = self.alu_swap(value);
-		self.ram.write(self.registers.get_hl(), res);
+		self.bus_write(self.registers.get_hl(), res);
 
 		4
 	}
@@ -1088,8 +1311,11 @@ impl CPU {
 
 	fn call_n16(&mut self, _: &Instruction) -> MCycles {
 		let n16 = self.read_two_bytes();
-		self.stack_push_16(self.registers.pc);
+		let return_address = self.registers.pc;
+		self.tick_m_cycle();
+		self.stack_push_16(return_address);
 		self.registers.pc = n16;
+		self.push_call_frame(return_address.wrapping_sub(3), return_address, false);
 
 		6
 	}
@@ -1097,8 +1323,11 @@ impl CPU {
 	fn call_cc_n16(&mut self, instruction: &Instruction) -> MCycles {
 		let n16 = self.read_two_bytes();
 		if self.registers.cc(instruction.middle_u3()) {
-			self.stack_push_16(self.registers.pc);
+			let return_address = self.registers.pc;
+			self.tick_m_cycle();
+			self.stack_push_16(return_address);
 			self.registers.pc = n16;
+			self.push_call_frame(return_address.wrapping_sub(3), return_address, false);
 			6
 		} else {
 			3
@@ -1106,9 +1335,10 @@ impl CPU {
 	}
 
 	fn ret_cc(&mut self, instruction: &Instruction) -> MCycles {
+		// Checking the condition takes a cycle
+		self.tick_m_cycle();
 		if self.registers.cc(instruction.middle_u3()) {
-			let new_pc = self.stack_pop_16();
-			self.registers.pc = new_pc;
+			self.return_from_call();
 			5
 		} else {
 			2
@@ -1116,15 +1346,13 @@ impl CPU {
 	}
 
 	fn ret(&mut self, _: &Instruction) -> MCycles {
-		let new_pc = self.stack_pop_16();
-		self.registers.pc = new_pc;
+		self.return_from_call();
 
 		4
 	}
 
 	fn reti(&mut self, _: &Instruction) -> MCycles {
-		let new_pc = self.stack_pop_16();
-		self.registers.pc = new_pc;
+		self.return_from_call();
 		self.ime = Ime::Set;
 
 		4
@@ -1132,8 +1360,11 @@ impl CPU {
 
 	fn rst(&mut self, instruction: &Instruction) -> MCycles {
 		let addr = CPU::get_rst_address(instruction.middle_u3());
-		self.stack_push_16(self.registers.pc);
+		let return_address = self.registers.pc;
+		self.tick_m_cycle();
+		self.stack_push_16(return_address);
 		self.registers.pc = addr;
+		self.push_call_frame(return_address.wrapping_sub(1), return_address, false);
 
 		4
 	}
@@ -1160,21 +1391,23 @@ impl CPU {
 			self.registers.get_r16(index)
 		};
 
+		self.tick_m_cycle();
 		self.stack_push_16(value);
 
 		4
 	}
 
 	fn stack_push_16(&mut self, value: u16) {
-		let bytes = value.to_le_bytes();
+		let [lo, hi] = value.to_le_bytes();
 
-		let mut sp = self.registers.get_sp();
-		sp = sp.wrapping_sub(2);
-
-		self.ram.write(sp, bytes[0]);
-		self.ram.write(sp + 1, bytes[1]);
+		// The high byte is pushed first
+		let sp = self.registers.get_sp().wrapping_sub(1);
+		self.registers.set_sp(sp);
+		self.bus_write(sp, hi);
 
+		let sp = sp.wrapping_sub(1);
 		self.registers.set_sp(sp);
+		self.bus_write(sp, lo);
 	}
 
 	fn pop_r16(&mut self, instruction: &Instruction) -> MCycles {
@@ -1195,7 +1428,7 @@ impl CPU {
 
 	fn stack_pop_16(&mut self) -> u16 {
 		let mut sp = self.registers.get_sp();
-		let values = [self.ram.read(sp), self.ram.read(sp + 1)];
+		let values = [self.bus_read(sp), self.bus_read(sp + 1)];
 		let value = u16::from_le_bytes(values);
 		sp = sp.wrapping_add(2);
 		self.registers.set_sp(sp);
@@ -1315,9 +1548,24 @@ impl CPU {
 	}
 
 	fn stop(&mut self, _: &Instruction) -> MCycles {
-		self.read_byte();
-		self.mode = Mode::VeryLowPower;
-		// TODO: Reset DIV register
+		let button_held = self.ram.joypad_line_low();
+		let interrupt_pending = self.ram.pending_interrupt().is_some();
+
+		// STOP skips the byte after it unless an interrupt is pending
+		if !interrupt_pending {
+			self.read_byte();
+		}
+
+		if button_held {
+			// STOP mode is never entered while a button is held and DIV is left alone.
+			// Without a pending interrupt, the CPU ends up in HALT instead
+			if !interrupt_pending {
+				self.mode = Mode::LowPower;
+			}
+		} else {
+			self.mode = Mode::VeryLowPower;
+			self.timer.reset_div(&mut self.ram);
+		}
 
 		1
 	}
@@ -1349,22 +1597,47 @@ impl CPU {
 	}
 
 	fn handle_interrupts(&mut self) -> MCycles {
-		if self.ime != Ime::Set {
+		if self.ime != Ime::Set || self.ram.pending_interrupt().is_none() {
 			return 0;
 		}
 
-		let pending_interrupt = self.ram.pending_interrupt();
+		self.ime = Ime::Off;
 
-		if let Some(interrupt) = pending_interrupt {
-			self.stack_push_16(self.registers.pc);
-			self.registers.pc = interrupt.handler_address();
-			self.mode = Mode::NormalSpeed;
-			self.ime = Ime::Off;
-			self.ram.clear_interrupt(interrupt);
-			5
+		// With EI right before a HALT that hit the halt bug, the return address is the HALT itself
+		let return_address = if self.halt_bug_active {
+			self.halt_bug_active = false;
+			self.registers.pc.wrapping_sub(1)
 		} else {
-			0
+			self.registers.pc
+		};
+		let [lo, hi] = return_address.to_le_bytes();
+
+		// Two internal cycles, then the return address is pushed one byte at a time
+		self.tick_m_cycle();
+		self.tick_m_cycle();
+
+		let sp = self.registers.get_sp().wrapping_sub(1);
+		self.registers.set_sp(sp);
+		self.bus_write(sp, hi);
+
+		// The interrupt is only picked after the high byte is pushed. If that push overwrote IE and nothing is
+		// pending anymore, the dispatch is cancelled and execution continues from 0x0000
+		let pending_interrupt = self.ram.pending_interrupt();
+
+		let sp = sp.wrapping_sub(1);
+		self.registers.set_sp(sp);
+		self.bus_write(sp, lo);
+
+		match pending_interrupt {
+			Some(interrupt) => {
+				self.registers.pc = interrupt.handler_address();
+				self.ram.clear_interrupt(interrupt);
+			}
+			None => self.registers.pc = 0x0000,
 		}
+		self.push_call_frame(return_address, return_address, true);
+
+		5
 	}
 }
 
@@ -1386,6 +1659,8 @@ mod test {
 	use super::super::super::ram::{TestRamOperations};
 	use std::ptr::fn_addr_eq;
 	use crate::cpu::Mode::{LowPower, NormalSpeed, VeryLowPower};
+	use crate::ram::Interrupt;
+	use crate::input::Button;
 
 	#[test]
 	fn test_new_cpu() {
@@ -1404,6 +1679,76 @@ mod test {
 		));
 	}
 
+	#[test]
+	fn test_operation_tables() {
+		let expected: [(u8, InstructionHandler, MCycles); 12] = [
+			(0x00, CPU::no_op, 1),
+			(0x01, CPU::ld_r16_n16, 3),
+			(0x34, CPU::inc_hl, 3),
+			(0x3C, CPU::inc_r8, 1),
+			(0x41, CPU::ld_r8_r8, 1),
+			(0x76, CPU::halt, 1),
+			(0x86, CPU::add_a_hl, 2),
+			(0xC3, CPU::jp_n16, 4),
+			(0xCD, CPU::call_n16, 6),
+			(0xE0, CPU::ldh_n16_a, 3),
+			(0xF3, CPU::di, 1),
+			(0xFF, CPU::rst, 4),
+		];
+		for (instruction, handler, cycles) in expected {
+			let operation = operation(instruction);
+			assert!(fn_addr_eq(operation.handler, handler), "{instruction:02X}");
+			assert_eq!(operation.cycles, cycles, "{instruction:02X}");
+		}
+
+		let expected_cb: [(u8, InstructionHandler); 6] = [
+			(0x00, CPU::rlc_r8),
+			(0x06, CPU::rlc_hl),
+			(0x37, CPU::swap_r8),
+			(0x7E, CPU::bit_u3_hl),
+			(0x80, CPU::res_u3_r8),
+			(0xFF, CPU::set_u3_r8),
+		];
+		for (instruction, handler) in expected_cb {
+			assert!(fn_addr_eq(cb_operation(instruction).handler, handler), "CB {instruction:02X}");
+		}
+
+		// Exactly the 11 unused opcodes lock up the CPU
+		let illegal = [0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD];
+		for instruction in 0..=255u8 {
+			let locks_up = fn_addr_eq(operation(instruction).handler, CPU::illegal as InstructionHandler);
+			assert_eq!(locks_up, illegal.contains(&instruction), "{instruction:02X}");
+		}
+
+		let mut cpu = CPU::new();
+		cpu.ram.test_load(0, vec![0o313, 0o021]);
+		let (instruction, op) = cpu.get_operation();
+		assert_eq!(instruction, 0o021);
+		assert!(fn_addr_eq(op, CPU::rl_r8 as InstructionHandler));
+	}
+
+	#[test]
+	fn test_operation_cycles() {
+		// The cycle metadata should match what the handlers actually take
+		for instruction in 0..=255u8 {
+			let operation = operation(instruction);
+			if operation.cycles_not_taken.is_some() || [0o020, 0o166, 0o313].contains(&instruction) {
+				continue;
+			}
+
+			let mut cpu = CPU::new();
+			cpu.registers.set_sp(0xC000);
+			cpu.ram.test_load(0, vec![instruction, 0, 0]);
+			assert_eq!(cpu.execute(false), operation.cycles, "Cycle mismatch for {}", operation.format);
+		}
+
+		for instruction in 0..=255u8 {
+			let mut cpu = CPU::new();
+			cpu.ram.test_load(0, vec![0o313, instruction]);
+			assert_eq!(cpu.execute(false), cb_operation(instruction).cycles, "Cycle mismatch for {}", cb_operation(instruction).format);
+		}
+	}
+
 	#[test]
 	fn test_get_instruction_value() {
 		let instruction: Instruction = 0b_1100_0111;
@@ -1413,10 +1758,7 @@ mod test {
 	#[test]
 	fn test_pc() {
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0b0000_1001);
-		cpu.ram.write(1, 0b1100_1100);
-		cpu.ram.write(2, 0b1101_1100);
-		cpu.ram.write(3, 0b0001_1100);
+		cpu.ram.test_load(0, vec![0b0000_1001, 0b1100_1100, 0b1101_1100, 0b0001_1100]);
 
 		assert_eq!(cpu.registers.pc, 0);
 		assert_eq!(cpu.read_byte(), 0b0000_1001);
@@ -1429,11 +1771,62 @@ mod test {
 		assert_eq!(cpu.registers.pc, 4);
 	}
 
+	#[test]
+	fn test_memory_access_timing() {
+		// TIMA increments every 4 M-cycles. LDH A, (n8) reads TIMA on its third cycle
+		let mut cpu = CPU::new();
+		cpu.ram.test_load(0, vec![0o360, 0x05]);
+		cpu.ram.write(0xFF07, 0b0000_0101);
+		assert_eq!(cpu.execute(false), 3);
+		assert_eq!(cpu.registers.a, 0, "TIMA should not have incremented before it was read");
+
+		// The read now happens on the fourth cycle, so TIMA has incremented before being read
+		let mut cpu = CPU::new();
+		cpu.ram.test_load(0, vec![0o000, 0o360, 0x05]);
+		cpu.ram.write(0xFF07, 0b0000_0101);
+		cpu.execute(false);
+		cpu.execute(false);
+		assert_eq!(cpu.registers.a, 1, "TIMA should have incremented before it was read");
+	}
+
+	#[test]
+	fn test_push_order() {
+		// The high byte is written first, so it is what lands on IE when SP wraps around to 0x0000
+		let mut cpu = CPU::new();
+		cpu.ram.test_load(0, vec![0o305]);
+		cpu.registers.set_sp(0x0000);
+		cpu.registers.b = 0xAB;
+		cpu.registers.c = 0xCD;
+		assert_eq!(cpu.execute(false), 4);
+		assert_eq!(cpu.ram.read(0xFFFF), 0xAB);
+		assert_eq!(cpu.ram.read(0xFFFE), 0xCD);
+		assert_eq!(cpu.registers.get_sp(), 0xFFFE);
+	}
+
+	#[test]
+	fn test_bus_activity() {
+		let mut cpu = CPU::new();
+		cpu.ram.test_load(0, vec![0o305]);
+		cpu.registers.set_sp(0xC002);
+		cpu.registers.b = 0xAB;
+		cpu.registers.c = 0xCD;
+		cpu.record_bus_activity(true);
+		cpu.execute(false);
+
+		assert_eq!(cpu.take_bus_activity(), vec![
+			BusActivity::Read { address: 0x0000, value: 0o305 },
+			BusActivity::Internal,
+			BusActivity::Write { address: 0xC001, value: 0xAB },
+			BusActivity::Write { address: 0xC000, value: 0xCD },
+		]);
+		assert!(cpu.take_bus_activity().is_empty());
+	}
+
 	#[test]
 	fn test_add_a_r8() {
 		// Add 0 to 'A' register
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0o200);
+		cpu.ram.test_load(0, vec![0o200]);
 		let (instruction, op) = cpu.get_operation();
 		assert!(fn_addr_eq(op, CPU::add_a_r8 as InstructionHandler));
 
@@ -1463,7 +1856,7 @@ mod test {
 	#[test]
 	fn test_addc_a_a8() {
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0o210); // ADC A, B
+		cpu.ram.test_load(0, vec![0o210]); // ADC A, B
 		let (instruction, operation) = cpu.get_operation();
 		assert!(fn_addr_eq(operation, CPU::addc_a_r8 as InstructionHandler));
 
@@ -1481,7 +1874,7 @@ mod test {
 	#[test]
 	fn test_add_hl_r16() {
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0o031);
+		cpu.ram.test_load(0, vec![0o031]);
 		let (instruction, operation) = cpu.get_operation();
 		assert!(fn_addr_eq(operation, CPU::add_hl_r16 as InstructionHandler));
 
@@ -1535,7 +1928,7 @@ mod test {
 	#[test]
 	fn test_sub_a_r8() {
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0o220);
+		cpu.ram.test_load(0, vec![0o220]);
 		let (instruction, op) = cpu.get_operation();
 		assert!(fn_addr_eq(op, CPU::sub_a_r8 as InstructionHandler));
 
@@ -1566,7 +1959,7 @@ mod test {
 	#[test]
 	fn test_subc_a_a8() {
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0o235);
+		cpu.ram.test_load(0, vec![0o235]);
 		let (instruction, op) = cpu.get_operation();
 		assert!(fn_addr_eq(op, CPU::subc_a_r8 as InstructionHandler));
 
@@ -1585,7 +1978,7 @@ mod test {
 	#[test]
 	fn test_cpl() {
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0o057);
+		cpu.ram.test_load(0, vec![0o057]);
 		let (instruction, op) = cpu.get_operation();
 		assert!(fn_addr_eq(op, CPU::cpl as InstructionHandler));
 		cpu.registers.f = 0b1111_0000; // Pre-set all flag bits
@@ -1603,7 +1996,7 @@ mod test {
 	#[test]
 	fn test_and() {
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0o241);
+		cpu.ram.test_load(0, vec![0o241]);
 		let (instruction, op) = cpu.get_operation();
 		assert!(fn_addr_eq(op, CPU::and_a_r8 as InstructionHandler));
 		cpu.registers.f = 0b0101_0000; // Test that these values get overrode
@@ -1626,7 +2019,7 @@ mod test {
 	#[test]
 	fn test_xor_a_r8() {
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0o252);
+		cpu.ram.test_load(0, vec![0o252]);
 		let (instruction, op) = cpu.get_operation();
 		assert!(fn_addr_eq(op, CPU::xor_a_r8 as InstructionHandler));
 		cpu.registers.f = 0b1111_0000; // Test that these values get overrode
@@ -1652,7 +2045,7 @@ mod test {
 	#[test]
 	fn test_or_a_r8() {
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0o263);
+		cpu.ram.test_load(0, vec![0o263]);
 		let (instruction, op) = cpu.get_operation();
 		assert!(fn_addr_eq(op, CPU::or_a_r8 as InstructionHandler));
 		cpu.registers.f = 0b1111_0000; // Test that these values get overrode
@@ -1678,7 +2071,7 @@ mod test {
 	#[test]
 	fn test_inc() {
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0o04);
+		cpu.ram.test_load(0, vec![0o04]);
 		let (instruction, op) = cpu.get_operation();
 		assert!(fn_addr_eq(op, CPU::inc_r8 as InstructionHandler));
 
@@ -1702,8 +2095,7 @@ mod test {
 	#[test]
 	fn test_inc_r16_and_dec_r16() {
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0o063);
-		cpu.ram.write(1, 0o073);
+		cpu.ram.test_load(0, vec![0o063, 0o073]);
 		let (inc_instruction, inc_operation) = cpu.get_operation();
 		let (dec_instruction, dec_operation) = cpu.get_operation();
 		assert!(fn_addr_eq(inc_operation, CPU::inc_r16 as InstructionHandler));
@@ -1719,7 +2111,7 @@ mod test {
 	#[test]
 	fn test_dec() {
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0o05);
+		cpu.ram.test_load(0, vec![0o05]);
 		let (_, op) = cpu.get_operation();
 		assert!(fn_addr_eq(op, CPU::dec_r8 as InstructionHandler));
 
@@ -1745,7 +2137,7 @@ mod test {
 	#[test]
 	fn test_ld_r8_r8() {
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0o100);
+		cpu.ram.test_load(0, vec![0o100]);
 
 		let (instruction, operation) = cpu.get_operation();
 		assert!(fn_addr_eq(operation, CPU::ld_r8_r8 as InstructionHandler));
@@ -1815,12 +2207,12 @@ mod test {
 	fn test_ld_hl_r8() {
 		// Load b on to b
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0o163);
+		cpu.ram.test_load(0, vec![0o163]);
 		let (instruction, op) = cpu.get_operation();
 		assert!(fn_addr_eq(op, CPU::ld_hl_r8 as InstructionHandler));
 
 		// Set location
-		let location = 1029u16;
+		let location = 0xC405u16;
 		cpu.registers.set_hl(location);
 		assert_eq!(cpu.registers.get_hl(), location);
 
@@ -1848,7 +2240,7 @@ mod test {
 		assert!(fn_addr_eq(op, CPU::ld_hl_n8 as InstructionHandler));
 
 		// Set location
-		let location = 9291u16;
+		let location = 0xC44Bu16;
 		cpu.registers.set_hl(location);
 		assert_eq!(cpu.registers.get_hl(), location);
 
@@ -1887,7 +2279,7 @@ mod test {
 	fn test_cp_a_r8() {
 		// Compare A with D
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0o272);
+		cpu.ram.test_load(0, vec![0o272]);
 		let (instruction, op) = cpu.get_operation();
 		assert!(fn_addr_eq(op, CPU::cp_a_r8 as InstructionHandler));
 
@@ -1939,7 +2331,7 @@ mod test {
 
 		// RLA
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0o027);
+		cpu.ram.test_load(0, vec![0o027]);
 		let (_, op) = cpu.get_operation();
 		assert!(fn_addr_eq(op, CPU::rl_a as InstructionHandler));
 
@@ -1951,7 +2343,7 @@ mod test {
 
 		// RLC A
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0o007);
+		cpu.ram.test_load(0, vec![0o007]);
 		let (_, op) = cpu.get_operation();
 		assert!(fn_addr_eq(op, CPU::rlc_a as InstructionHandler));
 	}
@@ -2008,7 +2400,7 @@ mod test {
 
 		// RRA
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0o037);
+		cpu.ram.test_load(0, vec![0o037]);
 		let (_, op) = cpu.get_operation();
 		assert!(fn_addr_eq(op, CPU::rr_a as InstructionHandler));
 
@@ -2020,7 +2412,7 @@ mod test {
 
 		// RRC A
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0o017);
+		cpu.ram.test_load(0, vec![0o017]);
 		let (_, op) = cpu.get_operation();
 		assert!(fn_addr_eq(op, CPU::rrc_a as InstructionHandler));
 
@@ -2151,14 +2543,14 @@ mod test {
 	fn test_call_cc_n16() {
 		let mut cpu = CPU::new();
 		cpu.ram.test_load(0, vec![0o314, 0b1111_0110, 0, 0o314, 0b1111_0110, 0]);
-		cpu.registers.set_sp(100);
+		cpu.registers.set_sp(0xC064);
 		let (instruction, op) = cpu.get_operation();
 		assert!(fn_addr_eq(op, CPU::call_cc_n16 as InstructionHandler));
 
 		// Condition not met
-		assert_eq!(cpu.registers.get_sp(), 100);
+		assert_eq!(cpu.registers.get_sp(), 0xC064);
 		cpu.run_operation((instruction, op));
-		assert_eq!(cpu.registers.get_sp(), 100);
+		assert_eq!(cpu.registers.get_sp(), 0xC064);
 		assert_eq!(cpu.registers.pc, 3);
 
 		// Condition met
@@ -2166,10 +2558,10 @@ mod test {
 		let (instruction, op) = cpu.get_operation();
 		assert!(fn_addr_eq(op, CPU::call_cc_n16 as InstructionHandler));
 		cpu.run_operation((instruction, op));
-		assert_eq!(cpu.registers.get_sp(), 98);
+		assert_eq!(cpu.registers.get_sp(), 0xC062);
 		assert_eq!(cpu.registers.pc, 0b1111_0110);
-		assert_eq!(cpu.ram.read(99), 0);
-		assert_eq!(cpu.ram.read(98), 6);
+		assert_eq!(cpu.ram.read(0xC063), 0);
+		assert_eq!(cpu.ram.read(0xC062), 6);
 	}
 
 	#[test]
@@ -2180,11 +2572,11 @@ mod test {
 		assert!(fn_addr_eq(op, CPU::ret as InstructionHandler));
 
 		// Push return address onto stack
-		cpu.registers.set_sp(100);
+		cpu.registers.set_sp(0xC064);
 		cpu.stack_push_16(0b0000_1111_1010_1010);
 
 		cpu.run_operation((instruction, op));
-		assert_eq!(cpu.registers.get_sp(), 100);
+		assert_eq!(cpu.registers.get_sp(), 0xC064);
 		assert_eq!(cpu.registers.pc, 0b0000_1111_1010_1010);
 	}
 
@@ -2192,31 +2584,31 @@ mod test {
 	fn test_push_r16() {
 		let mut cpu = CPU::new();
 		cpu.ram.test_load(0, vec![0o325, 0o365]);
-		cpu.registers.set_sp(100);
+		cpu.registers.set_sp(0xC064);
 		let (instruction, op) = cpu.get_operation();
 		assert!(fn_addr_eq(op, CPU::push_r16 as InstructionHandler));
 
 		cpu.registers.d = 0b1111_0000;
 		cpu.registers.e = 0b1010_1010;
 		cpu.run_operation((instruction, op));
-		assert_eq!(cpu.registers.get_sp(), 98);
-		assert_eq!(cpu.ram.read(99), 0b1111_0000);
-		assert_eq!(cpu.ram.read(98), 0b1010_1010);
+		assert_eq!(cpu.registers.get_sp(), 0xC062);
+		assert_eq!(cpu.ram.read(0xC063), 0b1111_0000);
+		assert_eq!(cpu.ram.read(0xC062), 0b1010_1010);
 
 		cpu.registers.a = 0b0000_1111;
 		cpu.registers.f = 0b0101_1111;
 		let (instruction, op) = cpu.get_operation();
 		cpu.run_operation((instruction, op));
-		assert_eq!(cpu.registers.get_sp(), 96);
-		assert_eq!(cpu.ram.read(97), 0b0000_1111);
-		assert_eq!(cpu.ram.read(96), 0b0101_0000);
+		assert_eq!(cpu.registers.get_sp(), 0xC060);
+		assert_eq!(cpu.ram.read(0xC061), 0b0000_1111);
+		assert_eq!(cpu.ram.read(0xC060), 0b0101_0000);
 	}
 
 	#[test]
 	fn test_pop_r16() {
 		let mut cpu = CPU::new();
 		cpu.ram.test_load(0, vec![0o301, 0o361]);
-		cpu.registers.set_sp(100);
+		cpu.registers.set_sp(0xC064);
 		let (instruction, op) = cpu.get_operation();
 		assert!(fn_addr_eq(op, CPU::pop_r16 as InstructionHandler));
 
@@ -2227,38 +2619,38 @@ mod test {
 		assert_eq!(cpu.registers.b , 0b1111_0000);
 		assert_eq!(cpu.registers.c , 0b1010_1010);
 		assert_eq!(cpu.registers.f, 0b0000_0000);
-		assert_eq!(cpu.registers.get_sp(), 98);
+		assert_eq!(cpu.registers.get_sp(), 0xC062);
 
 		let (instruction, op) = cpu.get_operation();
 		cpu.run_operation((instruction, op));
 		assert_eq!(cpu.registers.a , 0b0000_1111);
 		assert_eq!(cpu.registers.f , 0b0101_0000);
-		assert_eq!(cpu.registers.get_sp(), 100);
+		assert_eq!(cpu.registers.get_sp(), 0xC064);
 	}
 
 	#[test]
 	fn test_j() {
 		// JP n16
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0o303);
+		cpu.ram.test_load(0, vec![0o303]);
 		let (_, op) = cpu.get_operation();
 		assert!(fn_addr_eq(op, CPU::jp_n16 as InstructionHandler));
 
 		// JP HL
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0o351);
+		cpu.ram.test_load(0, vec![0o351]);
 		let (_, op) = cpu.get_operation();
 		assert!(fn_addr_eq(op, CPU::jp_hl as InstructionHandler));
 
 		// JP cc n16
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0o302);
+		cpu.ram.test_load(0, vec![0o302]);
 		let (_, op) = cpu.get_operation();
 		assert!(fn_addr_eq(op, CPU::jp_cc_n16 as InstructionHandler));
 
 		// JR n16
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0o030);
+		cpu.ram.test_load(0, vec![0o030]);
 		let (_, op) = cpu.get_operation();
 		assert!(fn_addr_eq(op, CPU::jr_n16 as InstructionHandler));
 	}
@@ -2318,17 +2710,17 @@ mod test {
 		assert!(fn_addr_eq(op, CPU::bit_u3_hl as InstructionHandler));
 
 		// Run with bit set
-		cpu.registers.set_hl(10);
-		cpu.ram.write(10, 0b0100_0000);
+		cpu.registers.set_hl(0xC00A);
+		cpu.ram.write(0xC00A, 0b0100_0000);
 		cpu.run_operation((instruction, op));
 		assert_eq!(cpu.registers.f, 0b0010_0000);
 
 		// Run with bit unset
-		cpu.ram.write(10, 0b1011_1111);
+		cpu.ram.write(0xC00A, 0b1011_1111);
 		cpu.run_operation((instruction, op));
 		assert_eq!(cpu.registers.f, 0b1010_0000);
 
-		cpu.ram.write(10, 0b0000_0000);
+		cpu.ram.write(0xC00A, 0b0000_0000);
 		cpu.run_operation((instruction, op));
 		assert_eq!(cpu.registers.f, 0b1010_0000);
 	}
@@ -2358,14 +2750,14 @@ mod test {
 		assert!(fn_addr_eq(op, CPU::res_u3_hl as InstructionHandler));
 
 		// Run with bit set
-		cpu.registers.set_hl(25);
-		cpu.ram.write(25, 0b0110_0101);
+		cpu.registers.set_hl(0xC019);
+		cpu.ram.write(0xC019, 0b0110_0101);
 		cpu.run_operation((instruction, op));
-		assert_eq!(cpu.ram.read(25), 0b0110_0001);
+		assert_eq!(cpu.ram.read(0xC019), 0b0110_0001);
 
 		// Run with bit unset
 		cpu.run_operation((instruction, op));
-		assert_eq!(cpu.ram.read(25), 0b0110_0001);
+		assert_eq!(cpu.ram.read(0xC019), 0b0110_0001);
 	}
 
 	#[test]
@@ -2393,14 +2785,14 @@ mod test {
 		assert!(fn_addr_eq(op, CPU::set_u3_hl as InstructionHandler));
 
 		// Run with bit set
-		cpu.registers.set_hl(25);
-		cpu.ram.write(25, 0b0110_0001);
+		cpu.registers.set_hl(0xC019);
+		cpu.ram.write(0xC019, 0b0110_0001);
 		cpu.run_operation((instruction, op));
-		assert_eq!(cpu.ram.read(25), 0b0110_0101);
+		assert_eq!(cpu.ram.read(0xC019), 0b0110_0101);
 
 		// Run with bit unset
 		cpu.run_operation((instruction, op));
-		assert_eq!(cpu.ram.read(25), 0b0110_0101);
+		assert_eq!(cpu.ram.read(0xC019), 0b0110_0101);
 	}
 
 	#[test]
@@ -2433,11 +2825,32 @@ mod test {
 	}
 
 	#[test]
-	fn test_no_ops_for_unknown() {
+	fn test_illegal_opcode_locks_cpu() {
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0o374);
+		cpu.ram.test_load(0, vec![0o374, 0o074]);
 		let (_, op) = cpu.get_operation();
-		assert!(fn_addr_eq(op, CPU::no_op as InstructionHandler));
+		assert!(fn_addr_eq(op, CPU::illegal as InstructionHandler));
+
+		cpu.registers.pc = 0;
+		cpu.execute(false);
+		assert!(cpu.is_locked());
+		assert_eq!(cpu.registers.pc, 1);
+
+		// Neither interrupts nor further execution get the CPU going again
+		cpu.ime = Ime::Set;
+		cpu.ram.write(0xFFFF, 0b1111_1111);
+		cpu.ram.write(0xFF0F, 0b1111_1111);
+		for _ in 0..10 {
+			assert_eq!(cpu.execute(false), 1);
+		}
+		assert!(cpu.is_locked());
+		assert_eq!(cpu.registers.pc, 1);
+		assert_eq!(cpu.registers.a, 0);
+
+		// The timer keeps running
+		let cycles = cpu.timer.cycles;
+		cpu.execute(false);
+		assert_eq!(cpu.timer.cycles, cycles + 1);
 	}
 
 	#[test]
@@ -2470,22 +2883,257 @@ mod test {
 		assert_eq!(cpu.ram.read(cpu.registers.pc), 99);
 	}
 
+	#[test]
+	fn test_interrupt_dispatch() {
+		let mut cpu = CPU::new();
+		cpu.ram.test_load(0x0100, vec![0o373, 0o000, 0o000]); // EI, NOP, NOP
+		cpu.registers.pc = 0x0100;
+		cpu.registers.set_sp(0xC010);
+		cpu.ram.write(0xFFFF, 0b0000_0101);
+		cpu.ram.request_interrupt(Interrupt::Timer);
+		cpu.ram.request_interrupt(Interrupt::VBlank);
+
+		// The interrupt can't be dispatched until the instruction after EI has run
+		cpu.execute(false);
+		assert_eq!(cpu.registers.pc, 0x0101);
+		cpu.execute(false);
+		assert_eq!(cpu.registers.pc, 0x0102);
+
+		cpu.ram.test_load(0x0040, vec![0o000]);
+		assert_eq!(cpu.execute(false), 6, "Dispatching should take 5 cycles followed by the handler's NOP");
+		assert_eq!(cpu.registers.pc, 0x0041, "VBlank has the highest priority");
+		assert_eq!(cpu.ime, Ime::Off);
+		assert_eq!(cpu.registers.get_sp(), 0xC00E);
+		assert_eq!(cpu.ram.read(0xC00F), 0x01);
+		assert_eq!(cpu.ram.read(0xC00E), 0x02);
+		assert_eq!(cpu.ram.read(0xFF0F) & 0b0000_0101, 0b0000_0100, "Only the dispatched interrupt is cleared");
+	}
+
+	#[test]
+	fn test_interrupt_cancelled_by_ie_push() {
+		// Pushing the high byte of PC=0x0234 onto IE disables the pending VBlank interrupt
+		let mut cpu = CPU::new();
+		cpu.ram.test_load(0x0234, vec![0o000]);
+		cpu.ram.test_load(0x0000, vec![0o000]);
+		cpu.registers.pc = 0x0234;
+		cpu.registers.set_sp(0x0000);
+		cpu.ime = Ime::Set;
+		cpu.ram.write(0xFFFF, 0b0000_0001);
+		cpu.ram.request_interrupt(Interrupt::VBlank);
+
+		cpu.execute(false);
+		assert_eq!(cpu.registers.pc, 0x0001, "The dispatch should be cancelled, jumping to 0x0000");
+		assert_eq!(cpu.ram.read(0xFFFF), 0x02);
+		assert_eq!(cpu.ram.read(0xFF0F) & 0b0000_0001, 0b0000_0001, "The interrupt should still be requested");
+
+		// A push that leaves another interrupt enabled redirects the dispatch to it
+		let mut cpu = CPU::new();
+		cpu.ram.test_load(0x0400, vec![0o000]);
+		cpu.ram.test_load(0x0050, vec![0o000]);
+		cpu.registers.pc = 0x0400;
+		cpu.registers.set_sp(0x0000);
+		cpu.ime = Ime::Set;
+		cpu.ram.write(0xFFFF, 0b0000_0001);
+		cpu.ram.request_interrupt(Interrupt::VBlank);
+		cpu.ram.request_interrupt(Interrupt::Timer);
+
+		cpu.execute(false);
+		assert_eq!(cpu.registers.pc, 0x0051, "The timer interrupt should be dispatched instead");
+		assert_eq!(cpu.ram.read(0xFF0F) & 0b0000_0101, 0b0000_0001, "VBlank should still be requested");
+	}
+
+	#[test]
+	fn test_ei_halt_bug_dispatch() {
+		// EI followed by HALT with an interrupt pending returns to the HALT itself
+		let mut cpu = CPU::new();
+		cpu.ram.test_load(0x0100, vec![0o373, 0o166]);
+		cpu.ram.test_load(0x0048, vec![0o000]);
+		cpu.registers.pc = 0x0100;
+		cpu.registers.set_sp(0xC010);
+		cpu.ram.write(0xFFFF, 0b0000_0010);
+		cpu.ram.request_interrupt(Interrupt::Stat);
+
+		cpu.execute(false);
+		cpu.execute(false);
+		assert_eq!(cpu.mode, NormalSpeed);
+		assert_eq!(cpu.ime, Ime::Set);
+
+		cpu.execute(false);
+		assert_eq!(cpu.registers.pc, 0x0049, "The handler's first instruction shouldn't be repeated");
+		assert_eq!(cpu.ram.read(0xC00F), 0x01);
+		assert_eq!(cpu.ram.read(0xC00E), 0x01, "The return address should point at the HALT");
+	}
+
+	#[test]
+	fn test_call_stack() {
+		let mut cpu = CPU::new();
+		cpu.ram.test_load(0x0100, vec![0o315, 0x00, 0x02]); // CALL $0200
+		cpu.ram.test_load(0x0200, vec![0o377]); // RST $38
+		cpu.ram.test_load(0x0038, vec![0o000, 0o311]); // NOP, RET
+		cpu.ram.test_load(0x0048, vec![0o000, 0o331]); // NOP, RETI
+		cpu.registers.pc = 0x0100;
+		cpu.registers.set_sp(0xC010);
+		cpu.track_call_stack(true);
+
+		cpu.execute(false);
+		cpu.execute(false);
+		let frames = cpu.call_stack().unwrap().frames();
+		assert_eq!(frames.len(), 2);
+		assert_eq!(frames[0].caller_pc, 0x0100);
+		assert_eq!(frames[0].target, 0x0200);
+		assert_eq!(frames[0].return_address, 0x0103);
+		assert_eq!(frames[0].stack_pointer, 0xC00E);
+		assert_eq!(frames[1].caller_pc, 0x0200);
+		assert_eq!(frames[1].target, 0x0038);
+		assert!(!frames[1].interrupt);
+
+		// The interrupt is dispatched before the NOP and returns to it
+		cpu.ime = Ime::Set;
+		cpu.ram.write(0xFFFF, 0b0000_0010);
+		cpu.ram.request_interrupt(Interrupt::Stat);
+		cpu.execute(false);
+		let frame = cpu.call_stack().unwrap().frames()[2];
+		assert!(frame.interrupt);
+		assert_eq!(frame.caller_pc, 0x0038);
+		assert_eq!(frame.target, 0x0048);
+
+		for _ in 0..3 {
+			cpu.execute(false);
+		}
+		assert_eq!(cpu.registers.pc, 0x0201);
+		assert_eq!(cpu.call_stack().unwrap().frames().len(), 1);
+		assert!(cpu.call_stack().unwrap().imbalances().is_empty());
+	}
+
+	#[test]
+	fn test_halt_waits_for_interrupt() {
+		let mut cpu = CPU::new();
+		cpu.ram.test_load(0, vec![0o166, 0o000, 0o166]);
+		cpu.registers.set_sp(0xC064);
+		cpu.ram.write(0xFFFF, 0b0000_0100);
+
+		// IME not set. HALT only burns cycles until an interrupt is pending
+		cpu.execute(false);
+		assert_eq!(cpu.mode, LowPower);
+		assert_eq!(cpu.registers.pc, 1);
+
+		let cycles_before = cpu.timer.cycles;
+		for _ in 0..10 {
+			assert_eq!(cpu.execute(false), 1, "Halted CPU should only take a single cycle");
+		}
+		assert_eq!(cpu.registers.pc, 1, "Nothing should be executed while halted");
+		assert_eq!(cpu.timer.cycles, cycles_before + 10, "The timer should keep running while halted");
+
+		// A disabled interrupt doesn't wake the CPU
+		cpu.ram.request_interrupt(Interrupt::VBlank);
+		cpu.execute(false);
+		assert_eq!(cpu.mode, LowPower);
+
+		// Wake up without dispatching the interrupt as IME is not set
+		cpu.ram.request_interrupt(Interrupt::Timer);
+		assert_eq!(cpu.execute(false), 2, "Waking up should cost an extra cycle on top of the NOP");
+		assert_eq!(cpu.mode, NormalSpeed);
+		assert_eq!(cpu.registers.pc, 2, "The NOP after HALT should have run");
+		assert_eq!(cpu.ram.pending_interrupt(), Some(Interrupt::Timer), "The interrupt should still be pending");
+
+		// Halt again, this time with IME set so the interrupt gets dispatched on wake up
+		cpu.ram.clear_interrupt(Interrupt::Timer);
+		cpu.ime = Ime::Set;
+		cpu.execute(false);
+		assert_eq!(cpu.mode, LowPower);
+		assert_eq!(cpu.registers.pc, 3);
+
+		cpu.ram.request_interrupt(Interrupt::Timer);
+		cpu.execute(false);
+		assert_eq!(cpu.mode, NormalSpeed);
+		assert_eq!(cpu.ime, Ime::Off);
+		assert_eq!(cpu.registers.pc, Interrupt::Timer.handler_address() + 1, "The handler's first instruction should have run");
+		assert_eq!(cpu.ram.read(0xC062), 3, "The return address should point after the HALT");
+	}
+
 	#[test]
 	fn test_stop() {
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0o020);
+		cpu.ram.test_load(0, vec![0o020]);
 		let (instruction, op) = cpu.get_operation();
 		assert!(fn_addr_eq(op, CPU::stop as InstructionHandler));
 
+		cpu.timer.set_system_counter(&mut cpu.ram, 0x1200);
 		cpu.run_operation((instruction, op));
 		assert_eq!(cpu.mode, VeryLowPower);
 		assert_eq!(cpu.registers.pc, 2, "Stop is a 2 byte instruction where the second byte is ignored");
+		assert_eq!(cpu.ram.read(0xFF04), 0, "DIV should be reset");
+	}
+
+	#[test]
+	fn test_stop_variants() {
+		// No button held with an interrupt pending. STOP mode is entered as a 1 byte instruction
+		let mut cpu = CPU::new();
+		cpu.ram.test_load(0, vec![0o020, 0o000]);
+		cpu.ram.write(0xFFFF, 0b0000_0001);
+		cpu.ram.request_interrupt(Interrupt::VBlank);
+		cpu.timer.set_system_counter(&mut cpu.ram, 0x1200);
+		let i_data = cpu.get_operation();
+		cpu.run_operation(i_data);
+		assert_eq!(cpu.mode, VeryLowPower);
+		assert_eq!(cpu.registers.pc, 1);
+		assert_eq!(cpu.ram.read(0xFF04), 0, "DIV should be reset");
+
+		// Button held with no interrupt pending. HALT is entered instead as a 2 byte instruction
+		let mut cpu = CPU::new();
+		cpu.ram.test_load(0, vec![0o020, 0o000]);
+		cpu.ram.set_button(Button::A, true);
+		cpu.timer.set_system_counter(&mut cpu.ram, 0x1200);
+		let i_data = cpu.get_operation();
+		cpu.run_operation(i_data);
+		assert_eq!(cpu.mode, LowPower);
+		assert_eq!(cpu.registers.pc, 2);
+		assert_eq!(cpu.ram.read(0xFF04), 0x12, "DIV should not be reset");
+
+		// Button held with an interrupt pending. STOP is a 1 byte instruction that does nothing
+		let mut cpu = CPU::new();
+		cpu.ram.test_load(0, vec![0o020, 0o000]);
+		cpu.ram.write(0xFFFF, 0b0001_0000);
+		cpu.ram.set_button(Button::A, true);
+		cpu.timer.set_system_counter(&mut cpu.ram, 0x1200);
+		let i_data = cpu.get_operation();
+		cpu.run_operation(i_data);
+		assert_eq!(cpu.mode, NormalSpeed);
+		assert_eq!(cpu.registers.pc, 1);
+		assert_eq!(cpu.ram.read(0xFF04), 0x12, "DIV should not be reset");
+	}
+
+	#[test]
+	fn test_stop_wakes_up_on_joypad() {
+		let mut cpu = CPU::new();
+		cpu.ram.test_load(0, vec![0o020, 0o000, 0o074]);
+		cpu.execute(false);
+		assert!(cpu.stopped());
+
+		let cycles_before = cpu.timer.cycles;
+		for _ in 0..10 {
+			cpu.execute(false);
+		}
+		assert!(cpu.stopped());
+		assert_eq!(cpu.registers.pc, 2, "Nothing should be executed while stopped");
+		assert_eq!(cpu.timer.cycles, cycles_before, "The timer should be stopped");
+
+		// Only selected lines can wake the CPU up
+		cpu.ram.write(0xFF00, 0b0010_0000);
+		cpu.ram.set_button(Button::A, true);
+		cpu.execute(false);
+		assert!(cpu.stopped());
+
+		cpu.ram.set_button(Button::Up, true);
+		cpu.execute(false);
+		assert!(!cpu.stopped());
+		assert_eq!(cpu.registers.a, 1, "Execution should resume after STOP");
 	}
 
 	#[test]
 	fn test_daa() {
 		let mut cpu = CPU::new();
-		cpu.ram.write(0, 0o047);
+		cpu.ram.test_load(0, vec![0o047]);
 		let (instruction, op) = cpu.get_operation();
 		assert!(fn_addr_eq(op, CPU::daa as InstructionHandler));
 
@@ -2542,10 +3190,6 @@ mod test {
 		assert_eq!(cpu.registers.a, 1, "0x60 should have been subtracted");
 	}
 
-	fn test_tma_edge_case() {
-		// TODO: If a TMA write is executed on the same M-cycle as the content of TMA is transferred to TIMA due to a timer overflow, the old value is transferred to TIMA.
-	}
-
 	#[test]
 	fn test_instruction() {
 		let instruction = 0b0000_0000_1000_1111 as Instruction;
@@ -0,0 +1,335 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use crate::cpu::CPU;
+use crate::cpu::disassembler::disassemble;
+
+/// Number of lines shown on either side of a divergence
+const DIVERGENCE_CONTEXT_LINES: usize = 5;
+const DISASSEMBLY_SEPARATOR: &str = " ; ";
+
+/// Receives every traced line. Returning an error stops the trace
+pub trait TraceSink: Send {
+	fn record(&mut self, line: &str) -> Result<(), TraceError>;
+}
+
+#[derive(Debug)]
+pub enum TraceError {
+	Io(io::Error),
+	Divergence(TraceDivergence),
+}
+
+impl fmt::Display for TraceError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			TraceError::Io(e) => write!(f, "Failed to write trace: {e}"),
+			TraceError::Divergence(divergence) => write!(f, "{divergence}"),
+		}
+	}
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct TraceDivergence {
+	/// 1-indexed line within the reference log
+	pub line_number: usize,
+	pub expected: Option<String>,
+	pub actual: String,
+	/// Matching lines leading up to the divergence
+	pub before: Vec<String>,
+	/// Reference lines following the expected line
+	pub after: Vec<String>,
+}
+
+impl fmt::Display for TraceDivergence {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		writeln!(f, "Trace diverged at line {}", self.line_number)?;
+		for line in &self.before {
+			writeln!(f, "  {line}")?;
+		}
+		writeln!(f, "- {}", self.expected.as_deref().unwrap_or("<end of reference log>"))?;
+		writeln!(f, "+ {}", self.actual)?;
+		for line in &self.after {
+			writeln!(f, "  {line}")?;
+		}
+
+		Ok(())
+	}
+}
+
+/// Writes every line to a file or any other writer
+pub struct WriteSink<W: Write + Send> {
+	writer: W,
+}
+
+impl<W: Write + Send> WriteSink<W> {
+	pub fn new(writer: W) -> Self {
+		Self { writer }
+	}
+}
+
+impl WriteSink<BufWriter<File>> {
+	pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+		Ok(Self::new(BufWriter::new(File::create(path)?)))
+	}
+}
+
+impl<W: Write + Send> TraceSink for WriteSink<W> {
+	fn record(&mut self, line: &str) -> Result<(), TraceError> {
+		writeln!(self.writer, "{line}").map_err(TraceError::Io)
+	}
+}
+
+/// Compares every line against a reference log, stopping at the first line that differs
+pub struct CompareSink<R: BufRead + Send> {
+	reference: R,
+	line_number: usize,
+	history: VecDeque<String>,
+}
+
+impl<R: BufRead + Send> CompareSink<R> {
+	pub fn new(reference: R) -> Self {
+		Self {
+			reference,
+			line_number: 0,
+			history: VecDeque::with_capacity(DIVERGENCE_CONTEXT_LINES),
+		}
+	}
+
+	fn next_reference_line(&mut self) -> Result<Option<String>, TraceError> {
+		let mut line = String::new();
+		let bytes_read = self.reference.read_line(&mut line).map_err(TraceError::Io)?;
+		if bytes_read == 0 {
+			return Ok(None);
+		}
+
+		Ok(Some(line.trim_end().to_string()))
+	}
+}
+
+impl CompareSink<BufReader<File>> {
+	pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+		Ok(Self::new(BufReader::new(File::open(path)?)))
+	}
+}
+
+impl<R: BufRead + Send> TraceSink for CompareSink<R> {
+	fn record(&mut self, line: &str) -> Result<(), TraceError> {
+		self.line_number += 1;
+		let expected = self.next_reference_line()?;
+
+		// Reference logs don't have the disassembly column, so it is left out of the comparison
+		let actual = line.split(DISASSEMBLY_SEPARATOR).next().unwrap_or(line);
+		if expected.as_deref() == Some(actual) {
+			if self.history.len() == DIVERGENCE_CONTEXT_LINES {
+				self.history.pop_front();
+			}
+			self.history.push_back(line.to_string());
+			return Ok(());
+		}
+
+		let mut after = vec![];
+		while after.len() < DIVERGENCE_CONTEXT_LINES {
+			match self.next_reference_line()? {
+				Some(line) => after.push(line),
+				None => break,
+			}
+		}
+
+		Err(TraceError::Divergence(TraceDivergence {
+			line_number: self.line_number,
+			expected,
+			actual: line.to_string(),
+			before: self.history.drain(..).collect(),
+			after,
+		}))
+	}
+}
+
+/// Limits which instructions end up in the trace. Every set filter needs to match
+#[derive(Default, Clone)]
+pub struct TraceFilter {
+	pub pc_range: Option<RangeInclusive<u16>>,
+	/// Number of instructions to skip before tracing starts
+	pub start_after: u64,
+	/// Only trace while this ROM bank is mapped into 0x4000-0x7FFF
+	pub rom_bank: Option<u16>,
+}
+
+/// Logs the CPU state before each instruction in the Gameboy Doctor format
+pub struct Tracer {
+	sink: Box<dyn TraceSink>,
+	filter: TraceFilter,
+	include_disassembly: bool,
+	instructions_seen: u64,
+	error: Option<TraceError>,
+}
+
+impl Tracer {
+	pub fn new(sink: impl TraceSink + 'static, filter: TraceFilter, include_disassembly: bool) -> Self {
+		Self {
+			sink: Box::new(sink),
+			filter,
+			include_disassembly,
+			instructions_seen: 0,
+			error: None,
+		}
+	}
+
+	/// Set once the sink fails or the trace diverges from the reference log. Nothing is traced afterward
+	pub fn error(&self) -> Option<&TraceError> {
+		self.error.as_ref()
+	}
+
+	pub fn divergence(&self) -> Option<&TraceDivergence> {
+		match &self.error {
+			Some(TraceError::Divergence(divergence)) => Some(divergence),
+			_ => None,
+		}
+	}
+
+	pub fn trace(&mut self, cpu: &CPU) {
+		self.instructions_seen += 1;
+		if self.error.is_some() || !self.should_trace(cpu) {
+			return;
+		}
+
+		let mut line = cpu.cpu_state();
+		if self.include_disassembly {
+			line.push_str(DISASSEMBLY_SEPARATOR);
+			line.push_str(&disassemble(&cpu.ram, cpu.registers.pc).to_string());
+		}
+
+		if let Err(e) = self.sink.record(&line) {
+			self.error = Some(e);
+		}
+	}
+
+	fn should_trace(&self, cpu: &CPU) -> bool {
+		if self.instructions_seen <= self.filter.start_after {
+			return false;
+		}
+
+		if let Some(pc_range) = &self.filter.pc_range && !pc_range.contains(&cpu.registers.pc) {
+			return false;
+		}
+
+		if let Some(rom_bank) = self.filter.rom_bank && cpu.ram.rom_bank() != rom_bank {
+			return false;
+		}
+
+		true
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::sync::{Arc, Mutex};
+	use crate::ram::{Interrupt, TestRamOperations};
+
+	#[derive(Clone, Default)]
+	struct SharedSink {
+		lines: Arc<Mutex<Vec<String>>>,
+	}
+
+	impl TraceSink for SharedSink {
+		fn record(&mut self, line: &str) -> Result<(), TraceError> {
+			self.lines.lock().unwrap().push(line.to_string());
+			Ok(())
+		}
+	}
+
+	fn nop_cpu() -> CPU {
+		let mut cpu = CPU::new();
+		cpu.ram.test_load(0, vec![0o074; 16]); // INC A
+		cpu
+	}
+
+	#[test]
+	fn test_trace() {
+		let sink = SharedSink::default();
+		let mut cpu = nop_cpu();
+		cpu.set_tracer(Some(Tracer::new(sink.clone(), TraceFilter::default(), true)));
+		cpu.execute(false);
+		cpu.execute(false);
+
+		let lines = sink.lines.lock().unwrap();
+		assert_eq!(lines.len(), 2);
+		assert_eq!(lines[0], "A:00 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:0000 PC:0000 PCMEM:3C,3C,3C,3C ; INC A");
+		assert_eq!(lines[1], "A:01 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:0000 PC:0001 PCMEM:3C,3C,3C,3C ; INC A");
+	}
+
+	#[test]
+	fn test_trace_filters() {
+		let sink = SharedSink::default();
+		let mut cpu = nop_cpu();
+		let filter = TraceFilter {
+			pc_range: Some(0..=4),
+			start_after: 2,
+			rom_bank: None,
+		};
+		cpu.set_tracer(Some(Tracer::new(sink.clone(), filter, false)));
+		for _ in 0..10 {
+			cpu.execute(false);
+		}
+
+		let lines = sink.lines.lock().unwrap();
+		let pcs = lines.iter().map(|line| line.split(' ').nth(9).unwrap()).collect::<Vec<&str>>();
+		assert_eq!(pcs, vec!["PC:0002", "PC:0003", "PC:0004"]);
+
+		// Bank 1 is always mapped without an MBC
+		let sink = SharedSink::default();
+		let mut cpu = nop_cpu();
+		let filter = TraceFilter { rom_bank: Some(2), ..Default::default() };
+		cpu.set_tracer(Some(Tracer::new(sink.clone(), filter, false)));
+		cpu.execute(false);
+		assert!(sink.lines.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn test_trace_interrupt() {
+		let sink = SharedSink::default();
+		let mut cpu = nop_cpu();
+		cpu.ram.test_load(0, vec![0xFB]); // EI
+		cpu.ram.test_load(0x40, vec![0o074; 4]);
+		cpu.registers.set_sp(0xD000);
+		cpu.ram.write(0xFFFF, 0b0000_0001);
+		cpu.ram.request_interrupt(Interrupt::VBlank);
+		cpu.set_tracer(Some(Tracer::new(sink.clone(), TraceFilter::default(), false)));
+		for _ in 0..4 {
+			cpu.execute(false);
+		}
+
+		// The interrupted PC isn't logged, the first instruction of the handler is
+		let lines = sink.lines.lock().unwrap();
+		let pcs = lines.iter().map(|line| line.split(' ').nth(9).unwrap()).collect::<Vec<&str>>();
+		assert_eq!(pcs, vec!["PC:0000", "PC:0001", "PC:0040", "PC:0041"]);
+	}
+
+	#[test]
+	fn test_compare() {
+		let reference = [
+			"A:00 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:0000 PC:0000 PCMEM:3C,3C,3C,3C",
+			"A:01 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:0000 PC:0001 PCMEM:3C,3C,3C,3C",
+			"A:05 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:0000 PC:0002 PCMEM:3C,3C,3C,3C",
+			"A:06 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:0000 PC:0003 PCMEM:3C,3C,3C,3C",
+		].join("\n");
+
+		let mut cpu = nop_cpu();
+		let sink = CompareSink::new(io::Cursor::new(reference.into_bytes()));
+		cpu.set_tracer(Some(Tracer::new(sink, TraceFilter::default(), true)));
+		for _ in 0..5 {
+			cpu.execute(false);
+		}
+
+		let divergence = cpu.tracer().unwrap().divergence().unwrap();
+		assert_eq!(divergence.line_number, 3);
+		assert_eq!(divergence.before.len(), 2);
+		assert!(divergence.expected.as_ref().unwrap().starts_with("A:05"));
+		assert!(divergence.actual.starts_with("A:02"));
+		assert_eq!(divergence.after.len(), 1);
+	}
+}
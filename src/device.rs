@@ -1,39 +1,64 @@
 use crate::cpu::CPU;
-use crate::ppu::PPU;
+use crate::cpu::call_stack::CallStack;
+use crate::cpu::trace::{TraceError, Tracer};
 use crate::tlu::{TLUData, TLU};
 use std::sync::mpsc::{Sender};
-use crate::dma::DMA;
+use crate::input::{Button, JoypadInput};
+use crate::mbc::rtc::Clock;
+use crate::rom::{Cartridge, CartridgeError, CartridgeHeader};
+use crate::lcd::Framebuffer;
+use crate::io_registers::POST_BOOT_VALUES;
+use crate::save::{read_save, write_save};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The timer's internal counter when the boot ROM hands over, which puts DIV at 0xAB
+const POST_BOOT_SYSTEM_COUNTER: u16 = 0xABCC;
+
+/// How often battery RAM gets written back while running, so a crash loses at most this much progress
+const AUTOSAVE_INTERVAL_FRAMES: u64 = 60 * 10;
 
 #[derive(Debug)]
 pub struct ImageData {
+	/// What's on the LCD
+	pub frame: Box<Framebuffer>,
 	pub tlu_data: TLUData,
+	/// Whether the cartridge's rumble motor is on, for frontends that can't rumble
+	pub rumble: bool,
 }
 
 pub struct Device {
 	cpu: CPU,
-	ppu: PPU,
 	tlu: TLU,
-	dma: DMA,
 
 	image_channel: Sender<ImageData>,
-	frame_counter: u64,
+	header: Option<CartridgeHeader>,
+
+	/// Where battery RAM is persisted. None for cartridges without a battery
+	save_path: Option<PathBuf>,
+	frames_since_save: u64,
+	/// The last failed autosave, kept until the frontend takes it
+	autosave_error: Option<io::Error>,
 }
 
 impl Device {
 	pub fn new(image_channel: Sender<ImageData>) -> Self {
 		Self {
 			cpu: CPU::new(),
-			ppu: PPU::new(),
 			tlu: TLU {},
-			dma: DMA::new(),
 			image_channel,
-			frame_counter: 0,
+			header: None,
+			save_path: None,
+			frames_since_save: 0,
+			autosave_error: None,
 		}
 	}
 
-	pub fn load(&mut self, rom: &[u8]) {
-		// Delegate load to ram
-		self.cpu.ram.load_rom(rom);
+	pub fn load(&mut self, rom: &[u8]) -> Result<(), CartridgeError> {
+		let cartridge = Cartridge::new(rom)?;
+		let header = cartridge.header.clone();
+		self.cpu.ram.load_cartridge(cartridge)?;
+		self.header = Some(header);
 
 		// Default values that get set after a typical boot screen
 		self.cpu.registers.a = 0x01;
@@ -47,23 +72,114 @@ impl Device {
 		self.cpu.registers.set_sp(0xFFFE);
 		self.cpu.registers.pc = 0x0100;
 
-		// TODO: Remove this. The below simulates VBlank progress. Once our PPU is online we don't need to worry about that shit
-		self.cpu.ram.write(0xFF44, 0x90); // Set LY to simulate some VBlank progress
+		for (address, value) in POST_BOOT_VALUES {
+			self.cpu.ram.unblocked_write(address, value);
+		}
+		self.cpu.timer.set_system_counter(&mut self.cpu.ram, POST_BOOT_SYSTEM_COUNTER);
+
+		Ok(())
 	}
 
-	pub fn tick(&mut self) {
-		let m_cycles = self.cpu.execute(false);
-		self.dma.tick_transfer(&mut self.cpu.ram, m_cycles);
+	/// The header of the loaded cartridge
+	pub fn cartridge_header(&self) -> Option<&CartridgeHeader> {
+		self.header.as_ref()
+	}
+
+	/// Loads battery RAM from `path` if it exists, and persists it there from now on.
+	/// Does nothing for cartridges without a battery. Must be called after loading the ROM
+	pub fn use_save_file(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+		if !self.cpu.ram.has_battery() {
+			return Ok(());
+		}
+
+		let path = path.as_ref();
+		if let Some(data) = read_save(path)? {
+			self.cpu.ram.load_save_data(&data);
+		}
+		self.save_path = Some(path.to_path_buf());
+		Ok(())
+	}
+
+	/// Writes battery RAM to the save file, if there is one
+	pub fn save(&mut self) -> io::Result<()> {
+		let Some(path) = &self.save_path else {
+			return Ok(());
+		};
+
+		write_save(path, &self.cpu.ram.save_data())?;
+		self.cpu.ram.mark_saved();
+		Ok(())
+	}
+
+	/// Takes the error from the last autosave that failed, if it hasn't been taken yet
+	pub fn take_autosave_error(&mut self) -> Option<io::Error> {
+		self.autosave_error.take()
+	}
+
+	pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+		self.cpu.set_tracer(tracer);
+	}
+
+	pub fn trace_error(&self) -> Option<&TraceError> {
+		self.cpu.tracer().and_then(Tracer::error)
+	}
+
+	/// The CPU hung on an illegal opcode and will never execute another instruction
+	pub fn is_locked(&self) -> bool {
+		self.cpu.is_locked()
+	}
+
+	/// Starts keeping a shadow call stack for backtraces, or drops it when disabled
+	pub fn track_call_stack(&mut self, enabled: bool) {
+		self.cpu.track_call_stack(enabled);
+	}
+
+	/// The calls in progress and any stack imbalances seen so far. Displays as a backtrace
+	pub fn call_stack(&self) -> Option<&CallStack> {
+		self.cpu.call_stack()
+	}
+
+	/// Where a cartridge RTC gets the time from. Must be set after loading
+	pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+		self.cpu.ram.set_clock(clock);
+	}
+
+	/// Called with the new state whenever a rumble cartridge turns its motor on or off
+	pub fn set_rumble_callback(&mut self, callback: Option<Box<dyn FnMut(bool) + Send>>) {
+		self.cpu.ram.set_rumble_callback(callback);
+	}
+
+	pub fn set_button(&mut self, button: Button, pressed: bool) {
+		self.cpu.ram.set_button(button, pressed);
+	}
 
-		self.ppu.tick(m_cycles, &mut self.cpu.ram);
+	pub fn tick(&mut self) {
+		// The CPU drives the timer, DMA and PPU as it accesses memory
+		self.cpu.execute(false);
+		if self.cpu.stopped() {
+			// The LCD is stopped along with the CPU
+			return;
+		}
 
-		// Only send frame data every ~70224 dots (60 FPS)
-		// Each M-cycle = 4 dots, so send every ~17556 ticks
-		self.frame_counter += m_cycles as u64;
-		if self.frame_counter >= 17556 {
+		// The PPU finishes a frame every 70224 dots (~60 FPS)
+		if let Some(frame) = self.cpu.take_frame() {
 			let tlu_data = self.tlu.update(&self.cpu.ram);
-			let _ = self.image_channel.send(ImageData {tlu_data});
-			self.frame_counter -= 17556;
+			let rumble = self.cpu.ram.rumble_active();
+			let _ = self.image_channel.send(ImageData {frame, tlu_data, rumble});
+			self.autosave();
+		}
+	}
+
+	fn autosave(&mut self) {
+		self.frames_since_save += 1;
+		if self.frames_since_save < AUTOSAVE_INTERVAL_FRAMES || !self.cpu.ram.has_unsaved_changes() {
+			return;
+		}
+
+		// Failed saves are retried on the next interval rather than every frame
+		self.frames_since_save = 0;
+		if let Err(e) = self.save() {
+			self.autosave_error = Some(e);
 		}
 	}
 }
\ No newline at end of file
@@ -1,39 +1,73 @@
 use crate::cpu::instruction::MCycles;
-use crate::ram::Ram;
+use crate::ram::{DmaBus, Ram};
 
+/// Copies 160 bytes into OAM, one per M-cycle, after a single M-cycle of setup
 pub struct DMA {
-	pub current_index: u16,
+	/// Source address of a transfer that starts on the next M-cycle
+	starting: Option<u16>,
+	transfer: Option<Transfer>,
+}
+
+struct Transfer {
+	source: u16,
+	index: u16,
 }
 
 const DMA_ADDRESS: u16 = 0xFF46;
 const DESTINATION_START_ADDRESS: u16 = 0xFE00;
-const MAX_LOWER_NIBBLE: u16 = 0x9F;
+const TRANSFER_LENGTH: u16 = 0xA0;
 
 impl DMA {
 	pub fn new() -> Self {
 		Self {
-			current_index: 0,
+			starting: None,
+			transfer: None,
 		}
 	}
 
+	/// Whether a transfer is starting or running, including the M-cycle in which it finishes
+	pub fn busy(&self) -> bool {
+		self.starting.is_some() || self.transfer.is_some()
+	}
+
 	pub fn tick_transfer(&mut self, ram: &mut Ram, cycles: MCycles) {
-		if !ram.dma_requested() { return; }
+		for _ in 0..cycles {
+			self.tick(ram);
+		}
+	}
+
+	fn tick(&mut self, ram: &mut Ram) {
+		// A restarted transfer replaces the old one, which keeps going during the new one's setup
+		if let Some(source) = self.starting.take() {
+			self.transfer = Some(Transfer { source, index: 0 });
+		}
 
-		let start_location: u16 = (ram.unblocked_read(DMA_ADDRESS) as u16) << 8;
+		if self.transfer.as_ref().is_some_and(|transfer| transfer.index == TRANSFER_LENGTH) {
+			self.transfer = None;
+		}
 
-		for _ in 0..cycles {
-			let source = start_location + self.current_index;
-			let destination = DESTINATION_START_ADDRESS + self.current_index;
-			ram.write(destination, ram.unblocked_read(source));
+		let bus = self.transfer.as_mut().map(|transfer| {
+			let address = transfer.source + transfer.index;
+			let value = ram.unblocked_read(address);
+			ram.unblocked_write(DESTINATION_START_ADDRESS + transfer.index, value);
+			transfer.index += 1;
 
-			self.current_index += 1;
+			DmaBus { address, value }
+		});
+		ram.set_dma_bus(bus);
 
-			if self.current_index > MAX_LOWER_NIBBLE { break; }
+		if ram.take_dma_request() {
+			self.starting = Some(DMA::source_address(ram.unblocked_read(DMA_ADDRESS)));
 		}
+	}
 
-		if self.current_index > MAX_LOWER_NIBBLE {
-			self.current_index = 0;
-			ram.clear_dma_request();
+	/// Sources from 0xE000 upwards read from WRAM through echo RAM, including 0xFE00 and 0xFF00
+	fn source_address(value: u8) -> u16 {
+		let source = (value as u16) << 8;
+		if source >= 0xE000 {
+			source - 0x2000
+		} else {
+			source
 		}
 	}
 }
@@ -48,41 +82,87 @@ mod tests {
 		let mut ram = Ram::new();
 
 		// Place values to be loaded
-		for i in 0..=0x9F {
+		for i in 0..TRANSFER_LENGTH {
 			ram.write(0x8000 + i, loaded_value);
 		}
 
 		// Test init values
 		let mut dma = DMA::new();
-		assert_eq!(ram.dma_requested(), false);
-		assert_eq!(dma.current_index, 0);
+		assert!(!dma.busy());
 		assert_eq!(ram.unblocked_read(DESTINATION_START_ADDRESS), 0);
 
-
-		// Test start_transfer activates dma
+		// The M-cycle after the write only sets up the transfer
 		ram.write(DMA_ADDRESS, 0x80);
-		assert_eq!(ram.dma_requested(), true);
+		dma.tick_transfer(&mut ram, 1);
+		assert!(dma.busy());
+		assert_eq!(ram.unblocked_read(DESTINATION_START_ADDRESS), 0);
+		assert_eq!(ram.read(DESTINATION_START_ADDRESS), 0, "OAM isn't blocked during setup");
 
-		// First tick: do a few bytes.
+		// First byte
 		dma.tick_transfer(&mut ram, 1);
-		assert_eq!(dma.current_index, 1);
 		assert_eq!(ram.unblocked_read(DESTINATION_START_ADDRESS), loaded_value);
+		assert_eq!(ram.read(DESTINATION_START_ADDRESS), 0xFF, "OAM is blocked during the transfer");
 
-		// Almost finish
-		dma.tick_transfer(&mut ram, (MAX_LOWER_NIBBLE - 1) as MCycles);
-		assert_eq!(dma.current_index, MAX_LOWER_NIBBLE);
-		assert_eq!(ram.dma_requested(), true);
+		// The last byte is copied on the 160th cycle, which is still blocked
+		dma.tick_transfer(&mut ram, (TRANSFER_LENGTH - 1) as MCycles);
+		assert_eq!(ram.unblocked_read(DESTINATION_START_ADDRESS + TRANSFER_LENGTH - 1), loaded_value);
+		assert_eq!(ram.read(DESTINATION_START_ADDRESS), 0xFF);
 
-		// Finish (and over finish)
-		dma.tick_transfer(&mut ram, 999);
-		assert_eq!(ram.dma_requested(), false);
-		assert_eq!(dma.current_index, 0);
+		dma.tick_transfer(&mut ram, 1);
+		assert!(!dma.busy());
+		assert_eq!(ram.read(DESTINATION_START_ADDRESS), loaded_value);
+		assert_eq!(ram.unblocked_read(DESTINATION_START_ADDRESS + TRANSFER_LENGTH), 0);
+	}
 
-		// Assert every address is correct
-		for i in 0..=MAX_LOWER_NIBBLE {
-			assert_eq!(ram.unblocked_read(DESTINATION_START_ADDRESS + i), loaded_value);
+	#[test]
+	fn test_dma_restart() {
+		let mut ram = Ram::new();
+		for i in 0..TRANSFER_LENGTH {
+			ram.write(0xC000 + i, 0x11);
+			ram.write(0xD000 + i, 0x22);
 		}
-		assert_eq!(ram.unblocked_read(DESTINATION_START_ADDRESS + MAX_LOWER_NIBBLE), loaded_value);
-		assert_eq!(ram.unblocked_read(DESTINATION_START_ADDRESS + MAX_LOWER_NIBBLE + 1), 0);
+
+		let mut dma = DMA::new();
+		ram.write(DMA_ADDRESS, 0xC0);
+		dma.tick_transfer(&mut ram, 11);
+		assert_eq!(ram.unblocked_read(DESTINATION_START_ADDRESS + 9), 0x11);
+
+		// The old transfer carries on while the new one is set up. Sources in echo RAM read WRAM
+		ram.write(DMA_ADDRESS, 0xF0);
+		dma.tick_transfer(&mut ram, 1);
+		assert_eq!(ram.unblocked_read(DESTINATION_START_ADDRESS + 10), 0x11);
+		assert_eq!(ram.read(DESTINATION_START_ADDRESS), 0xFF, "OAM stays blocked during the restart");
+
+		dma.tick_transfer(&mut ram, 1);
+		assert_eq!(ram.unblocked_read(DESTINATION_START_ADDRESS), 0x22);
+		assert_eq!(ram.unblocked_read(DESTINATION_START_ADDRESS + 11), 0x00);
+
+		dma.tick_transfer(&mut ram, TRANSFER_LENGTH as MCycles);
+		assert!(!dma.busy());
+		assert!((0..TRANSFER_LENGTH).all(|i| ram.read(DESTINATION_START_ADDRESS + i) == 0x22));
+	}
+
+	#[test]
+	fn test_dma_bus_conflicts() {
+		let mut ram = Ram::new();
+		ram.write(0xC000, 0x12);
+		ram.write(0xC001, 0x34);
+		ram.write(0x8000, 0x56);
+		ram.write(0xFF80, 0x78);
+
+		let mut dma = DMA::new();
+		ram.write(DMA_ADDRESS, 0xC0);
+		dma.tick_transfer(&mut ram, 3);
+
+		// DMA is using the external bus, so reads there see the byte being transferred
+		assert_eq!(ram.read(0xC000), 0x34);
+		assert_eq!(ram.read(0x0100), 0x34);
+		ram.write(0xC000, 0x9A);
+		assert_eq!(ram.unblocked_read(0xC000), 0x12, "Writes on the busy bus are lost");
+
+		// VRAM is on its own bus and HRAM is inside the CPU
+		assert_eq!(ram.read(0x8000), 0x56);
+		assert_eq!(ram.read(0xFF80), 0x78);
+		assert_eq!(ram.read(DMA_ADDRESS), 0xC0);
 	}
 }
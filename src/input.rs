@@ -1,3 +1,10 @@
+use crate::ram::{Interrupt, Ram};
+
+pub const JOYPAD_ADDRESS: u16 = 0xFF00;
+
+const SELECT_D_PAD_MASK: u8 = 0b0001_0000;
+const SELECT_BUTTONS_MASK: u8 = 0b0010_0000;
+
 /// Eight buttons so you'd need 8 bits but instead they
 /// Look at the buttons as two columns with four buttons each
 ///
@@ -7,11 +14,117 @@
 /// Right    A
 ///
 /// Thus you only need 6 bits
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Button {
+	Right,
+	Left,
+	Up,
+	Down,
+	A,
+	B,
+	Select,
+	Start,
+}
+
+impl Button {
+	/// The d-pad lives in the lower nibble and the buttons in the upper nibble
+	fn mask(&self) -> u8 {
+		match self {
+			Button::Right => 0b0000_0001,
+			Button::Left => 0b0000_0010,
+			Button::Up => 0b0000_0100,
+			Button::Down => 0b0000_1000,
+			Button::A => 0b0001_0000,
+			Button::B => 0b0010_0000,
+			Button::Select => 0b0100_0000,
+			Button::Start => 0b1000_0000,
+		}
+	}
+}
+
+/// Computes the value of the P1 register given the column select bits and the currently pressed buttons.
+/// A 0 bit means selected or pressed
+pub fn joypad_register(select_bits: u8, pressed_buttons: u8) -> u8 {
+	let mut lines = 0;
+	if select_bits & SELECT_D_PAD_MASK == 0 {
+		lines |= pressed_buttons & 0x0F;
+	}
+
+	if select_bits & SELECT_BUTTONS_MASK == 0 {
+		lines |= pressed_buttons >> 4;
+	}
+
+	0b1100_0000 | (select_bits & 0b0011_0000) | (!lines & 0x0F)
+}
+
+pub trait JoypadInput {
+	fn set_button(&mut self, button: Button, pressed: bool);
+	fn joypad_line_low(&self) -> bool;
+}
 
-pub struct Input<'a> {
-	memory: &'a [[u8;4]; 2]
+impl JoypadInput for Ram {
+	fn set_button(&mut self, button: Button, pressed: bool) {
+		let prev_lines = self.unblocked_read(JOYPAD_ADDRESS) & 0x0F;
+
+		let mut pressed_buttons = self.pressed_buttons();
+		if pressed {
+			pressed_buttons |= button.mask();
+		} else {
+			pressed_buttons &= !button.mask();
+		}
+		self.set_pressed_buttons(pressed_buttons);
+
+		// The interrupt fires when any selected line goes from high to low
+		let lines = self.unblocked_read(JOYPAD_ADDRESS) & 0x0F;
+		if prev_lines & !lines != 0 {
+			self.request_interrupt(Interrupt::Joypad);
+		}
+	}
+
+	fn joypad_line_low(&self) -> bool {
+		self.unblocked_read(JOYPAD_ADDRESS) & 0x0F != 0x0F
+	}
 }
 
-fn is_pressed() {
-	// 0 is pressed, 1 is not pressed
-}
\ No newline at end of file
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_joypad_register() {
+		let mut ram = Ram::new();
+		assert_eq!(ram.read(JOYPAD_ADDRESS), 0b1100_1111, "No buttons are pressed to start");
+		assert!(!ram.joypad_line_low());
+
+		// Pressing a button with both columns selected
+		ram.set_button(Button::Start, true);
+		assert_eq!(ram.read(JOYPAD_ADDRESS), 0b1100_0111);
+		assert!(ram.joypad_line_low());
+		assert_eq!(ram.pending_interrupt(), None, "Joypad interrupt is not enabled");
+		assert_eq!(ram.read(0xFF0F) & 0b0001_0000, 0b0001_0000, "Joypad interrupt should be requested");
+
+		// Only select the d-pad, hiding the start button
+		ram.write(JOYPAD_ADDRESS, 0b0010_0000);
+		assert_eq!(ram.read(JOYPAD_ADDRESS), 0b1110_1111);
+		assert!(!ram.joypad_line_low());
+
+		ram.set_button(Button::Left, true);
+		assert_eq!(ram.read(JOYPAD_ADDRESS), 0b1110_1101);
+
+		ram.set_button(Button::Left, false);
+		ram.set_button(Button::Start, false);
+		assert_eq!(ram.read(JOYPAD_ADDRESS), 0b1110_1111);
+	}
+
+	#[test]
+	fn test_joypad_interrupt_only_for_selected_lines() {
+		let mut ram = Ram::new();
+		ram.write(JOYPAD_ADDRESS, 0b0001_0000); // Only buttons selected
+
+		ram.set_button(Button::Down, true);
+		assert_eq!(ram.read(0xFF0F), 0b1110_0000, "D-pad isn't selected so no interrupt should be requested");
+
+		ram.set_button(Button::A, true);
+		assert_eq!(ram.read(0xFF0F), 0b1111_0000);
+	}
+}
@@ -0,0 +1,86 @@
+/// How the CPU sees a single I/O register in 0xFF00-0xFF7F or IE
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterMask {
+	/// Bits that always read as 1, either because they're unused or write only
+	pub read: u8,
+	/// Bits the CPU is able to change
+	pub write: u8,
+}
+
+impl RegisterMask {
+	const fn new(read: u8, write: u8) -> Self {
+		Self { read, write }
+	}
+}
+
+const READ_WRITE: RegisterMask = RegisterMask::new(0x00, 0xFF);
+const READ_ONLY: RegisterMask = RegisterMask::new(0x00, 0x00);
+const WRITE_ONLY: RegisterMask = RegisterMask::new(0xFF, 0xFF);
+/// Addresses without a register read as 0xFF and ignore writes
+const UNMAPPED: RegisterMask = RegisterMask::new(0xFF, 0x00);
+
+/// DMG masks for the register at `address`
+pub fn register_mask(address: u16) -> RegisterMask {
+	match address {
+		// P1, where the button lines are read only
+		0xFF00 => RegisterMask::new(0b1100_0000, 0b0011_0000),
+		// SB and SC
+		0xFF01 => READ_WRITE,
+		0xFF02 => RegisterMask::new(0b0111_1110, 0b1000_0001),
+		// DIV, TIMA, TMA and TAC
+		0xFF04..=0xFF06 => READ_WRITE,
+		0xFF07 => RegisterMask::new(0b1111_1000, 0b0000_0111),
+		// IF
+		0xFF0F => RegisterMask::new(0b1110_0000, 0b0001_1111),
+		// Sound. Lengths and frequencies can be written but not read back
+		0xFF10 => RegisterMask::new(0b1000_0000, 0b0111_1111),
+		0xFF11 | 0xFF16 => RegisterMask::new(0b0011_1111, 0xFF),
+		0xFF12 | 0xFF17 | 0xFF21 | 0xFF22 | 0xFF24 | 0xFF25 => READ_WRITE,
+		0xFF13 | 0xFF18 | 0xFF1B | 0xFF1D | 0xFF20 => WRITE_ONLY,
+		0xFF14 | 0xFF19 | 0xFF1E | 0xFF23 => RegisterMask::new(0b1011_1111, 0xFF),
+		0xFF1A => RegisterMask::new(0b0111_1111, 0b1000_0000),
+		0xFF1C => RegisterMask::new(0b1001_1111, 0b0110_0000),
+		// NR52, where the channel status bits are read only
+		0xFF26 => RegisterMask::new(0b0111_0000, 0b1000_0000),
+		// Wave RAM
+		0xFF30..=0xFF3F => READ_WRITE,
+		// STAT, where the mode and LY=LYC bits are read only
+		0xFF41 => RegisterMask::new(0b1000_0000, 0b0111_1000),
+		// LY
+		0xFF44 => READ_ONLY,
+		// LCDC, SCY, SCX, LYC, DMA, BGP, OBP0, OBP1, WY and WX
+		0xFF40..=0xFF4B => READ_WRITE,
+		0xFFFF => READ_WRITE,
+		_ => UNMAPPED,
+	}
+}
+
+/// Registers as the DMG boot ROM leaves them. DIV comes from the timer's internal counter instead
+pub const POST_BOOT_VALUES: [(u16, u8); 26] = [
+	(0xFF00, 0xCF), (0xFF02, 0x7E), (0xFF07, 0xF8), (0xFF0F, 0xE1),
+	(0xFF10, 0x80), (0xFF11, 0xBF), (0xFF12, 0xF3), (0xFF13, 0xFF), (0xFF14, 0xBF),
+	(0xFF16, 0x3F), (0xFF18, 0xFF), (0xFF19, 0xBF), (0xFF1A, 0x7F), (0xFF1B, 0xFF),
+	(0xFF1C, 0x9F), (0xFF1D, 0xFF), (0xFF1E, 0xBF), (0xFF20, 0xFF), (0xFF23, 0xBF),
+	(0xFF24, 0x77), (0xFF25, 0xF3), (0xFF26, 0xF1), (0xFF40, 0x91), (0xFF41, 0x85),
+	(0xFF46, 0xFF), (0xFF47, 0xFC),
+];
+
+/// Combines a CPU write with the bits of the register it can't change
+pub fn masked_write(address: u16, previous: u8, value: u8) -> u8 {
+	let mask = register_mask(address).write;
+	(previous & !mask) | (value & mask)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_masked_write() {
+		// STAT keeps its mode bits
+		assert_eq!(masked_write(0xFF41, 0b0000_0011, 0b1111_1100), 0b0111_1011);
+		assert_eq!(masked_write(0xFF44, 0x90, 0x00), 0x90, "LY is read only");
+		assert_eq!(masked_write(0xFF4C, 0x00, 0x12), 0x00, "Unmapped addresses ignore writes");
+		assert_eq!(register_mask(0xFF07).read | 0b101, 0b1111_1101, "Unused TAC bits read as 1");
+	}
+}
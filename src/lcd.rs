@@ -1,4 +1,11 @@
 use crate::ram::Ram;
+use crate::palette::Color;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+/// Shades for every pixel on the LCD, row by row
+pub type Framebuffer = [[Color; SCREEN_WIDTH]; SCREEN_HEIGHT];
 
 pub trait LCDControl {
 	fn set_lcd_enabled(&mut self, enabled: bool);
@@ -10,6 +17,7 @@ pub trait LCDControl {
 	fn obj_size_control(&self) -> bool;
 	fn obj_enabled(&self) -> bool;
 	fn bg_and_window_enabled(&self) -> bool;
+	fn bg_and_window_tile_address(&self, tile_index: u8) -> u16;
 	fn update_ly(&mut self, value: u8);
 }
 
@@ -54,12 +62,28 @@ impl LCDControl for Ram {
 		(self.unblocked_read(LCDC_ADDRESS) & 0b0000_0001) != 0
 	}
 
+	/// 0x8000 with unsigned indexes, or 0x9000 with signed ones
+	fn bg_and_window_tile_address(&self, tile_index: u8) -> u16 {
+		if self.bg_and_window_tile_data_control() {
+			0x8000 + tile_index as u16 * 16
+		} else {
+			0x9000u16.wrapping_add_signed(tile_index as i8 as i16 * 16)
+		}
+	}
+
 	fn update_ly(&mut self, value: u8) {
-		self.write(LY_ADDRESS, value);
+		self.unblocked_write(LY_ADDRESS, value);
 	}
 }
 
 pub const LCDC_ADDRESS: u16 = 0xFF40;
 pub const LY_ADDRESS: u16 = 0xFF44;
 pub const LYC_ADDRESS: u16 = 0xFF45;
-pub const STAT_ADDRESS: u16 = 0xFF41;
\ No newline at end of file
+pub const STAT_ADDRESS: u16 = 0xFF41;
+pub const SCY_ADDRESS: u16 = 0xFF42;
+pub const SCX_ADDRESS: u16 = 0xFF43;
+pub const BGP_ADDRESS: u16 = 0xFF47;
+pub const OBP0_ADDRESS: u16 = 0xFF48;
+pub const OBP1_ADDRESS: u16 = 0xFF49;
+pub const WY_ADDRESS: u16 = 0xFF4A;
+pub const WX_ADDRESS: u16 = 0xFF4B;
\ No newline at end of file
@@ -4,8 +4,11 @@ pub mod ram;
 pub mod tlu;
 pub mod palette;
 mod ppu;
-mod input;
-mod rom;
+pub mod input;
+pub mod rom;
 mod timer;
+mod io_registers;
 pub mod dma;
 pub mod lcd;
+pub mod mbc;
+pub mod save;
@@ -3,9 +3,15 @@ mod renderer;
 use crate::renderer::window_conf;
 use std::env;
 use std::fs::read;
-use std::sync::mpsc::{self, Sender};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
 use std::thread;
+use webboy::cpu::trace::{CompareSink, TraceFilter, Tracer, WriteSink};
 use webboy::device::{Device, ImageData};
+use macroquad::input::{is_quit_requested, prevent_quit};
+use webboy::save::save_path;
 
 #[macroquad::main(window_conf)]
 async fn main() {
@@ -13,27 +19,78 @@ async fn main() {
     let file_name = if args.len() > 1 {
         &args[1]
     } else {
-        println!("Usage: webboy <ROM file>");
+        println!("Usage: webboy <ROM file> [--trace <log file>] [--compare-trace <reference log file>]");
         return;
     };
 
     let rom: Vec<u8> = load_rom(file_name);
     let (tx, rx) = mpsc::channel::<ImageData>();
-    thread::spawn(move || {
-        webboy(rom, tx);
+    let mut device = Device::new(tx);
+    if let Err(e) = device.load(&rom) {
+        println!("Failed to load '{}': {}", file_name, e);
+        return;
+    }
+    match create_tracer(&args[2..]) {
+        Ok(tracer) => device.set_tracer(tracer),
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    }
+
+    let save_file = save_path(file_name);
+    if let Err(e) = device.use_save_file(&save_file) {
+        println!("Failed to load save file '{}': {}", save_file.display(), e);
+    }
+
+    let running = Arc::new(AtomicBool::new(true));
+    let emulator_running = running.clone();
+    let emulator = thread::spawn(move || {
+        webboy(device, save_file, &emulator_running);
     });
 
-    loop {
+    // Closing the window stops the emulator thread first so it can write its save
+    prevent_quit();
+    while !is_quit_requested() {
         renderer::handle(&rx).await;
     }
-}
 
-fn webboy(rom: Vec<u8>, tx: Sender<ImageData>) {
-    let mut device = Device::new(tx);
-    device.load(&rom);
+    running.store(false, Ordering::Relaxed);
+    if emulator.join().is_err() {
+        println!("The emulator thread panicked");
+    }
+}
 
-    loop {
+fn webboy(mut device: Device, save_file: PathBuf, running: &AtomicBool) {
+    while running.load(Ordering::Relaxed) {
         device.tick();
+
+        if let Some(e) = device.take_autosave_error() {
+            println!("Failed to write save file '{}': {}", save_file.display(), e);
+        }
+
+        if let Some(error) = device.trace_error() {
+            println!("{}", error);
+            break;
+        }
+    }
+
+    if let Err(e) = device.save() {
+        println!("Failed to write save file '{}': {}", save_file.display(), e);
+    }
+}
+
+fn create_tracer(options: &[String]) -> Result<Option<Tracer>, String> {
+    match options {
+        [flag, path] if flag == "--trace" => {
+            let sink = WriteSink::create(path).map_err(|e| format!("Failed to create trace file '{}': {}", path, e))?;
+            Ok(Some(Tracer::new(sink, TraceFilter::default(), true)))
+        }
+        [flag, path] if flag == "--compare-trace" => {
+            let sink = CompareSink::open(path).map_err(|e| format!("Failed to read reference log '{}': {}", path, e))?;
+            Ok(Some(Tracer::new(sink, TraceFilter::default(), false)))
+        }
+        _ => Ok(None),
     }
 }
 
@@ -0,0 +1,133 @@
+pub mod mbc1;
+pub mod mbc2;
+pub mod mbc3;
+pub mod mbc5;
+pub mod rtc;
+
+use mbc1::Mbc1;
+use mbc2::Mbc2;
+use mbc3::Mbc3;
+use mbc5::Mbc5;
+use rtc::Clock;
+use crate::rom::CartridgeError;
+
+/// Size of a single switchable ROM bank
+pub const ROM_BANK_SIZE: usize = 0x4000;
+/// Size of a single switchable external RAM bank
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+/// Maps the cartridge ROM into 0x0000-0x7FFF and its RAM into 0xA000-0xBFFF.
+/// Writes to ROM never change the ROM itself. They are sent here to control the mapping
+pub trait MemoryBankController: Send {
+	/// Handles a write anywhere within 0x0000-0x7FFF
+	fn write_register(&mut self, address: u16, value: u8);
+
+	/// Offset into the ROM for an address within 0x0000-0x7FFF
+	fn rom_offset(&self, address: u16) -> usize;
+
+	/// Offset into external RAM for an address within 0xA000-0xBFFF. None when RAM isn't accessible
+	fn ram_offset(&self, address: u16) -> Option<usize>;
+
+	/// The ROM bank mapped into 0x4000-0x7FFF
+	fn rom_bank(&self) -> u16;
+
+	/// The ROM bank mapped into 0x0000-0x3FFF
+	fn low_rom_bank(&self) -> u16 {
+		0
+	}
+
+	/// Bytes of external RAM to allocate given the size in the header
+	fn external_ram_size(&self, header_ram_size: usize) -> usize {
+		header_ram_size
+	}
+
+	/// Offsets past the end of the RAM wrap around, like the unused bank bits on hardware
+	fn read_ram(&self, ram: &[u8], address: u16) -> u8 {
+		match self.ram_offset(address) {
+			Some(offset) if !ram.is_empty() => ram[offset % ram.len()],
+			_ => 0xFF,
+		}
+	}
+
+	fn write_ram(&mut self, ram: &mut [u8], address: u16, value: u8) {
+		if let Some(offset) = self.ram_offset(address) && !ram.is_empty() {
+			let len = ram.len();
+			ram[offset % len] = value;
+		}
+	}
+
+	/// Whether a rumble motor is currently being driven
+	fn rumble_active(&self) -> bool {
+		false
+	}
+
+	/// Replaces the clock used by a real-time clock, if the cartridge has one
+	fn set_clock(&mut self, _: Box<dyn Clock>) {}
+
+	/// State beyond external RAM that has to survive between sessions, such as the RTC
+	fn save_state(&self) -> Vec<u8> {
+		vec![]
+	}
+
+	fn load_state(&mut self, _: &[u8]) {}
+}
+
+/// 32 KiB cartridges without a mapper, optionally with up to 8 KiB of RAM
+pub struct RomOnly;
+
+impl MemoryBankController for RomOnly {
+	fn write_register(&mut self, _: u16, _: u8) {}
+
+	fn rom_offset(&self, address: u16) -> usize {
+		address as usize
+	}
+
+	fn ram_offset(&self, address: u16) -> Option<usize> {
+		Some((address - 0xA000) as usize)
+	}
+
+	fn rom_bank(&self) -> u16 {
+		1
+	}
+}
+
+/// Picks the controller for the cartridge type in the header at 0x147
+pub fn create_mbc(cartridge_type: u8, rom_size: usize) -> Result<Box<dyn MemoryBankController>, CartridgeError> {
+	Ok(match cartridge_type {
+		0x00 | 0x08 | 0x09 => Box::new(RomOnly),
+		0x01..=0x03 => Box::new(Mbc1::new(rom_size)),
+		0x05 | 0x06 => Box::new(Mbc2::new(rom_size)),
+		0x0F | 0x10 => Box::new(Mbc3::new(rom_size, true)),
+		0x11..=0x13 => Box::new(Mbc3::new(rom_size, false)),
+		0x19..=0x1B => Box::new(Mbc5::new(rom_size, false)),
+		0x1C..=0x1E => Box::new(Mbc5::new(rom_size, true)),
+		_ => return Err(CartridgeError::UnsupportedCartridgeType(cartridge_type)),
+	})
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_rom_only() {
+		let mut mbc = RomOnly;
+		mbc.write_register(0x2000, 0x05);
+		assert_eq!(mbc.rom_bank(), 1, "Writes to ROM shouldn't switch banks");
+		assert_eq!(mbc.rom_offset(0x4123), 0x4123);
+
+		let mut ram = vec![0; RAM_BANK_SIZE];
+		mbc.write_ram(&mut ram, 0xA010, 0x42);
+		assert_eq!(mbc.read_ram(&ram, 0xA010), 0x42);
+
+		// Without RAM every read is open bus
+		let mut ram = vec![];
+		mbc.write_ram(&mut ram, 0xA010, 0x42);
+		assert_eq!(mbc.read_ram(&ram, 0xA010), 0xFF);
+	}
+
+	#[test]
+	fn test_unsupported_mbc() {
+		assert_eq!(create_mbc(0x20, 0x8000).err(), Some(CartridgeError::UnsupportedCartridgeType(0x20)));
+	}
+}
@@ -0,0 +1,125 @@
+use super::{MemoryBankController, RAM_BANK_SIZE, ROM_BANK_SIZE};
+
+/// Up to 2 MiB of ROM and 32 KiB of RAM
+pub struct Mbc1 {
+	rom_bank_count: usize,
+	ram_enabled: bool,
+	/// Lower 5 bits of the ROM bank. 0 is treated as 1
+	bank1: u8,
+	/// Upper 2 bits of the ROM bank, or the RAM bank in mode 1
+	bank2: u8,
+	/// Mode 1 also applies bank2 to 0x0000-0x3FFF and external RAM
+	advanced_banking: bool,
+}
+
+impl Mbc1 {
+	pub fn new(rom_size: usize) -> Self {
+		Self {
+			rom_bank_count: rom_size.div_ceil(ROM_BANK_SIZE).max(2),
+			ram_enabled: false,
+			bank1: 1,
+			bank2: 0,
+			advanced_banking: false,
+		}
+	}
+
+	fn bank(&self, bank: usize) -> usize {
+		bank % self.rom_bank_count
+	}
+}
+
+impl MemoryBankController for Mbc1 {
+	fn write_register(&mut self, address: u16, value: u8) {
+		match address {
+			0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+			0x2000..=0x3FFF => self.bank1 = (value & 0b1_1111).max(1),
+			0x4000..=0x5FFF => self.bank2 = value & 0b11,
+			_ => self.advanced_banking = value & 1 == 1,
+		}
+	}
+
+	fn rom_offset(&self, address: u16) -> usize {
+		let bank = match address {
+			0x0000..=0x3FFF => self.low_rom_bank() as usize,
+			_ => self.rom_bank() as usize,
+		};
+
+		bank * ROM_BANK_SIZE + (address as usize % ROM_BANK_SIZE)
+	}
+
+	fn ram_offset(&self, address: u16) -> Option<usize> {
+		if !self.ram_enabled {
+			return None;
+		}
+
+		let bank = if self.advanced_banking { self.bank2 as usize } else { 0 };
+		Some(bank * RAM_BANK_SIZE + (address - 0xA000) as usize)
+	}
+
+	fn rom_bank(&self) -> u16 {
+		self.bank((self.bank2 as usize) << 5 | self.bank1 as usize) as u16
+	}
+
+	fn low_rom_bank(&self) -> u16 {
+		if self.advanced_banking {
+			self.bank((self.bank2 as usize) << 5) as u16
+		} else {
+			0
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_rom_banking() {
+		// 2 MiB
+		let mut mbc = Mbc1::new(128 * ROM_BANK_SIZE);
+		assert_eq!(mbc.rom_bank(), 1);
+
+		// Bank 0 is remapped to 1, as are 0x20, 0x40 and 0x60
+		mbc.write_register(0x2000, 0x00);
+		assert_eq!(mbc.rom_bank(), 1);
+		mbc.write_register(0x4000, 0x01);
+		assert_eq!(mbc.rom_bank(), 0x21);
+
+		mbc.write_register(0x3FFF, 0xFF);
+		assert_eq!(mbc.rom_bank(), 0x3F, "Only the lower 5 bits are used");
+		assert_eq!(mbc.rom_offset(0x4001), 0x3F * ROM_BANK_SIZE + 1);
+		assert_eq!(mbc.rom_offset(0x0001), 1, "Bank 0 is fixed in mode 0");
+
+		// Mode 1 maps bank2 into 0x0000-0x3FFF too
+		mbc.write_register(0x6000, 0x01);
+		assert_eq!(mbc.low_rom_bank(), 0x20);
+		assert_eq!(mbc.rom_offset(0x0001), 0x20 * ROM_BANK_SIZE + 1);
+
+		// Bank numbers wrap around for smaller ROMs
+		let mut mbc = Mbc1::new(4 * ROM_BANK_SIZE);
+		mbc.write_register(0x2000, 0x05);
+		assert_eq!(mbc.rom_bank(), 1);
+	}
+
+	#[test]
+	fn test_ram_banking() {
+		let mut mbc = Mbc1::new(4 * ROM_BANK_SIZE);
+		let mut ram = vec![0; 4 * RAM_BANK_SIZE];
+		assert_eq!(mbc.read_ram(&ram, 0xA000), 0xFF, "RAM is disabled to start");
+
+		mbc.write_register(0x0000, 0x0A);
+		mbc.write_ram(&mut ram, 0xA000, 0x12);
+		assert_eq!(mbc.read_ram(&ram, 0xA000), 0x12);
+
+		// RAM banks only switch in mode 1
+		mbc.write_register(0x4000, 0x02);
+		assert_eq!(mbc.read_ram(&ram, 0xA000), 0x12);
+		mbc.write_register(0x6000, 0x01);
+		assert_eq!(mbc.read_ram(&ram, 0xA000), 0x00);
+		mbc.write_ram(&mut ram, 0xA000, 0x34);
+		assert_eq!(ram[2 * RAM_BANK_SIZE], 0x34);
+
+		mbc.write_register(0x1000, 0x00);
+		assert_eq!(mbc.read_ram(&ram, 0xA000), 0xFF);
+	}
+}
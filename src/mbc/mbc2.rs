@@ -0,0 +1,111 @@
+use super::{MemoryBankController, ROM_BANK_SIZE};
+
+/// 512 half-bytes of RAM built into the controller
+const RAM_SIZE: usize = 512;
+
+/// Up to 256 KiB of ROM along with its own 512×4-bit RAM
+pub struct Mbc2 {
+	rom_bank_count: usize,
+	ram_enabled: bool,
+	rom_bank: u8,
+}
+
+impl Mbc2 {
+	pub fn new(rom_size: usize) -> Self {
+		Self {
+			rom_bank_count: rom_size.div_ceil(ROM_BANK_SIZE).max(2),
+			ram_enabled: false,
+			rom_bank: 1,
+		}
+	}
+}
+
+impl MemoryBankController for Mbc2 {
+	fn write_register(&mut self, address: u16, value: u8) {
+		// Bit 8 of the address picks the register. Nothing is mapped to 0x4000-0x7FFF
+		match address {
+			0x0000..=0x3FFF if address & 0x0100 == 0 => self.ram_enabled = value & 0x0F == 0x0A,
+			0x0000..=0x3FFF => self.rom_bank = (value & 0x0F).max(1),
+			_ => {}
+		}
+	}
+
+	fn rom_offset(&self, address: u16) -> usize {
+		match address {
+			0x0000..=0x3FFF => address as usize,
+			_ => self.rom_bank() as usize * ROM_BANK_SIZE + (address as usize % ROM_BANK_SIZE),
+		}
+	}
+
+	/// The RAM is mirrored across all of 0xA000-0xBFFF
+	fn ram_offset(&self, address: u16) -> Option<usize> {
+		self.ram_enabled.then_some((address - 0xA000) as usize % RAM_SIZE)
+	}
+
+	fn rom_bank(&self) -> u16 {
+		(self.rom_bank as usize % self.rom_bank_count) as u16
+	}
+
+	fn external_ram_size(&self, _: usize) -> usize {
+		RAM_SIZE
+	}
+
+	/// Only the lower 4 bits are stored. The upper bits read as 1s
+	fn read_ram(&self, ram: &[u8], address: u16) -> u8 {
+		match self.ram_offset(address) {
+			Some(offset) => 0xF0 | ram[offset],
+			None => 0xFF,
+		}
+	}
+
+	fn write_ram(&mut self, ram: &mut [u8], address: u16, value: u8) {
+		if let Some(offset) = self.ram_offset(address) {
+			ram[offset] = value & 0x0F;
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_registers() {
+		let mut mbc = Mbc2::new(16 * ROM_BANK_SIZE);
+
+		// Bit 8 set selects the ROM bank
+		mbc.write_register(0x2100, 0x03);
+		assert_eq!(mbc.rom_bank(), 3);
+		mbc.write_register(0x0100, 0x00);
+		assert_eq!(mbc.rom_bank(), 1, "Bank 0 is remapped to 1");
+		mbc.write_register(0x3FFF, 0xFF);
+		assert_eq!(mbc.rom_bank(), 15);
+
+		// Bit 8 clear controls RAM, even when writing to the upper half of the register space
+		mbc.write_register(0x2000, 0x0A);
+		assert!(mbc.ram_enabled);
+		assert_eq!(mbc.rom_bank(), 15);
+
+		// Nothing is mapped to 0x4000-0x7FFF
+		mbc.write_register(0x4100, 0x02);
+		assert_eq!(mbc.rom_bank(), 15);
+	}
+
+	#[test]
+	fn test_ram() {
+		let mut mbc = Mbc2::new(4 * ROM_BANK_SIZE);
+		let mut ram = vec![0; mbc.external_ram_size(0)];
+		mbc.write_ram(&mut ram, 0xA000, 0x12);
+		assert_eq!(mbc.read_ram(&ram, 0xA000), 0xFF, "RAM is disabled to start");
+
+		mbc.write_register(0x0000, 0x0A);
+		mbc.write_ram(&mut ram, 0xA001, 0xAB);
+		assert_eq!(mbc.read_ram(&ram, 0xA001), 0xFB);
+
+		// Mirrored every 512 bytes
+		assert_eq!(mbc.read_ram(&ram, 0xA201), 0xFB);
+		assert_eq!(mbc.read_ram(&ram, 0xBE01), 0xFB);
+		mbc.write_ram(&mut ram, 0xBFFF, 0x07);
+		assert_eq!(mbc.read_ram(&ram, 0xA1FF), 0xF7);
+	}
+}
@@ -0,0 +1,168 @@
+use super::rtc::{Clock, Rtc, SystemClock};
+use super::{MemoryBankController, RAM_BANK_SIZE, ROM_BANK_SIZE};
+
+/// Up to 2 MiB of ROM, 32 KiB of RAM and an optional real-time clock
+pub struct Mbc3 {
+	rom_bank_count: usize,
+	ram_and_rtc_enabled: bool,
+	rom_bank: u8,
+	/// 0x00-0x07 selects a RAM bank, 0x08-0x0C an RTC register
+	ram_bank: u8,
+	rtc: Option<Rtc>,
+	/// Latching happens when 0x00 and then 0x01 is written
+	latch_prepared: bool,
+}
+
+impl Mbc3 {
+	pub fn new(rom_size: usize, has_rtc: bool) -> Self {
+		Self {
+			rom_bank_count: rom_size.div_ceil(ROM_BANK_SIZE).max(2),
+			ram_and_rtc_enabled: false,
+			rom_bank: 1,
+			ram_bank: 0,
+			rtc: has_rtc.then(|| Rtc::new(Box::new(SystemClock))),
+			latch_prepared: false,
+		}
+	}
+
+	fn selected_rtc_register(&self) -> Option<u8> {
+		(self.ram_and_rtc_enabled && self.rtc.is_some() && Rtc::is_register(self.ram_bank)).then_some(self.ram_bank)
+	}
+}
+
+impl MemoryBankController for Mbc3 {
+	fn write_register(&mut self, address: u16, value: u8) {
+		match address {
+			0x0000..=0x1FFF => self.ram_and_rtc_enabled = value & 0x0F == 0x0A,
+			0x2000..=0x3FFF => self.rom_bank = (value & 0x7F).max(1),
+			0x4000..=0x5FFF => self.ram_bank = value,
+			_ => {
+				if self.latch_prepared && value == 0x01 && let Some(rtc) = &mut self.rtc {
+					rtc.latch();
+				}
+				self.latch_prepared = value == 0x00;
+			}
+		}
+	}
+
+	fn rom_offset(&self, address: u16) -> usize {
+		match address {
+			0x0000..=0x3FFF => address as usize,
+			_ => self.rom_bank() as usize * ROM_BANK_SIZE + (address as usize % ROM_BANK_SIZE),
+		}
+	}
+
+	fn ram_offset(&self, address: u16) -> Option<usize> {
+		if !self.ram_and_rtc_enabled || self.ram_bank > 0x07 {
+			return None;
+		}
+
+		Some(self.ram_bank as usize * RAM_BANK_SIZE + (address - 0xA000) as usize)
+	}
+
+	fn rom_bank(&self) -> u16 {
+		(self.rom_bank as usize % self.rom_bank_count) as u16
+	}
+
+	fn read_ram(&self, ram: &[u8], address: u16) -> u8 {
+		if let Some(register) = self.selected_rtc_register() && let Some(rtc) = &self.rtc {
+			return rtc.read(register);
+		}
+
+		match self.ram_offset(address) {
+			Some(offset) if !ram.is_empty() => ram[offset % ram.len()],
+			_ => 0xFF,
+		}
+	}
+
+	fn write_ram(&mut self, ram: &mut [u8], address: u16, value: u8) {
+		if let Some(register) = self.selected_rtc_register() && let Some(rtc) = &mut self.rtc {
+			rtc.write(register, value);
+			return;
+		}
+
+		if let Some(offset) = self.ram_offset(address) && !ram.is_empty() {
+			let len = ram.len();
+			ram[offset % len] = value;
+		}
+	}
+
+	fn set_clock(&mut self, clock: Box<dyn Clock>) {
+		if let Some(rtc) = &mut self.rtc {
+			rtc.set_clock(clock);
+		}
+	}
+
+	fn save_state(&self) -> Vec<u8> {
+		self.rtc.as_ref().map(Rtc::save_state).unwrap_or_default()
+	}
+
+	fn load_state(&mut self, state: &[u8]) {
+		if let Some(rtc) = &mut self.rtc {
+			rtc.load_state(state);
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::mbc::rtc::test::FakeClock;
+
+	#[test]
+	fn test_banking() {
+		let mut mbc = Mbc3::new(128 * ROM_BANK_SIZE, false);
+		mbc.write_register(0x2000, 0x00);
+		assert_eq!(mbc.rom_bank(), 1);
+		mbc.write_register(0x2000, 0x7F);
+		assert_eq!(mbc.rom_bank(), 0x7F, "All 7 bits are used");
+		assert_eq!(mbc.rom_offset(0x4000), 0x7F * ROM_BANK_SIZE);
+
+		let mut ram = vec![0; 4 * RAM_BANK_SIZE];
+		mbc.write_register(0x0000, 0x0A);
+		mbc.write_register(0x4000, 0x03);
+		mbc.write_ram(&mut ram, 0xA000, 0x12);
+		assert_eq!(ram[3 * RAM_BANK_SIZE], 0x12);
+
+		// Without an RTC, its registers aren't mapped
+		mbc.write_register(0x4000, 0x08);
+		assert_eq!(mbc.read_ram(&ram, 0xA000), 0xFF);
+	}
+
+	#[test]
+	fn test_rtc_registers() {
+		let clock = FakeClock::default();
+		let mut mbc = Mbc3::new(4 * ROM_BANK_SIZE, true);
+		mbc.set_clock(Box::new(clock.clone()));
+		let mut ram = vec![];
+		mbc.write_register(0x0000, 0x0A);
+
+		clock.advance(75);
+		mbc.write_register(0x4000, 0x08);
+		assert_eq!(mbc.read_ram(&ram, 0xA000), 0, "Not latched yet");
+
+		// Only a 0x00 followed by 0x01 latches
+		mbc.write_register(0x6000, 0x01);
+		assert_eq!(mbc.read_ram(&ram, 0xA000), 0);
+		mbc.write_register(0x6000, 0x00);
+		mbc.write_register(0x6000, 0x01);
+		assert_eq!(mbc.read_ram(&ram, 0xA000), 15);
+		mbc.write_register(0x4000, 0x09);
+		assert_eq!(mbc.read_ram(&ram, 0xA000), 1);
+
+		// Writing the registers sets the clock
+		mbc.write_ram(&mut ram, 0xA000, 30);
+		mbc.write_register(0x6000, 0x00);
+		mbc.write_register(0x6000, 0x01);
+		assert_eq!(mbc.read_ram(&ram, 0xA000), 30);
+
+		// The RTC survives a save and load
+		let state = mbc.save_state();
+		let mut mbc = Mbc3::new(4 * ROM_BANK_SIZE, true);
+		mbc.set_clock(Box::new(clock.clone()));
+		mbc.load_state(&state);
+		mbc.write_register(0x0000, 0x0A);
+		mbc.write_register(0x4000, 0x09);
+		assert_eq!(mbc.read_ram(&ram, 0xA000), 30);
+	}
+}
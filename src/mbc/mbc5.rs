@@ -0,0 +1,101 @@
+use super::{MemoryBankController, RAM_BANK_SIZE, ROM_BANK_SIZE};
+
+const RUMBLE_MASK: u8 = 0b0000_1000;
+
+/// Up to 8 MiB of ROM and 128 KiB of RAM. Rumble carts use bit 3 of the RAM bank to drive the motor
+pub struct Mbc5 {
+	rom_bank_count: usize,
+	ram_enabled: bool,
+	/// 9 bit ROM bank. Unlike the other controllers, bank 0 can be mapped to 0x4000-0x7FFF
+	rom_bank: u16,
+	ram_bank: u8,
+	has_rumble: bool,
+	rumble_active: bool,
+}
+
+impl Mbc5 {
+	pub fn new(rom_size: usize, has_rumble: bool) -> Self {
+		Self {
+			rom_bank_count: rom_size.div_ceil(ROM_BANK_SIZE).max(2),
+			ram_enabled: false,
+			rom_bank: 1,
+			ram_bank: 0,
+			has_rumble,
+			rumble_active: false,
+		}
+	}
+}
+
+impl MemoryBankController for Mbc5 {
+	fn write_register(&mut self, address: u16, value: u8) {
+		match address {
+			0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+			0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | value as u16,
+			0x3000..=0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | ((value as u16 & 1) << 8),
+			0x4000..=0x5FFF if self.has_rumble => {
+				self.rumble_active = value & RUMBLE_MASK != 0;
+				self.ram_bank = value & 0x07;
+			}
+			0x4000..=0x5FFF => self.ram_bank = value & 0x0F,
+			_ => {}
+		}
+	}
+
+	fn rom_offset(&self, address: u16) -> usize {
+		match address {
+			0x0000..=0x3FFF => address as usize,
+			_ => self.rom_bank() as usize * ROM_BANK_SIZE + (address as usize % ROM_BANK_SIZE),
+		}
+	}
+
+	fn ram_offset(&self, address: u16) -> Option<usize> {
+		self.ram_enabled.then_some(self.ram_bank as usize * RAM_BANK_SIZE + (address - 0xA000) as usize)
+	}
+
+	fn rom_bank(&self) -> u16 {
+		(self.rom_bank as usize % self.rom_bank_count) as u16
+	}
+
+	fn rumble_active(&self) -> bool {
+		self.rumble_active
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_banking() {
+		// 8 MiB
+		let mut mbc = Mbc5::new(512 * ROM_BANK_SIZE, false);
+		mbc.write_register(0x2000, 0x00);
+		assert_eq!(mbc.rom_bank(), 0, "Bank 0 isn't remapped");
+		mbc.write_register(0x2FFF, 0x34);
+		mbc.write_register(0x3000, 0x01);
+		assert_eq!(mbc.rom_bank(), 0x134);
+		assert_eq!(mbc.rom_offset(0x4002), 0x134 * ROM_BANK_SIZE + 2);
+
+		let mut ram = vec![0; 16 * RAM_BANK_SIZE];
+		mbc.write_register(0x0000, 0x0A);
+		mbc.write_register(0x4000, 0x0F);
+		mbc.write_ram(&mut ram, 0xA000, 0x12);
+		assert_eq!(ram[15 * RAM_BANK_SIZE], 0x12);
+		assert!(!mbc.rumble_active());
+	}
+
+	#[test]
+	fn test_rumble() {
+		let mut mbc = Mbc5::new(4 * ROM_BANK_SIZE, true);
+		let mut ram = vec![0; 8 * RAM_BANK_SIZE];
+		mbc.write_register(0x0000, 0x0A);
+
+		mbc.write_register(0x4000, 0x0A);
+		assert!(mbc.rumble_active());
+		mbc.write_ram(&mut ram, 0xA000, 0x12);
+		assert_eq!(ram[2 * RAM_BANK_SIZE], 0x12, "The rumble bit isn't part of the RAM bank");
+
+		mbc.write_register(0x4000, 0x02);
+		assert!(!mbc.rumble_active());
+	}
+}
@@ -0,0 +1,315 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of bytes `Rtc::save_state` produces
+pub const RTC_STATE_SIZE: usize = 48;
+
+const SECONDS_REGISTER: u8 = 0x08;
+const MINUTES_REGISTER: u8 = 0x09;
+const HOURS_REGISTER: u8 = 0x0A;
+const DAYS_LOW_REGISTER: u8 = 0x0B;
+const DAYS_HIGH_REGISTER: u8 = 0x0C;
+
+const SECONDS_MASK: u8 = 0b0011_1111;
+const MINUTES_MASK: u8 = 0b0011_1111;
+const HOURS_MASK: u8 = 0b0001_1111;
+const HALT_MASK: u8 = 0b0100_0000;
+const DAY_CARRY_MASK: u8 = 0b1000_0000;
+
+/// Where the RTC gets the current time from, in seconds since the UNIX epoch
+pub trait Clock: Send {
+	fn now(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+	fn now(&self) -> u64 {
+		SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0)
+	}
+}
+
+#[derive(Clone, Copy, Default)]
+struct RtcTime {
+	seconds: u8,
+	minutes: u8,
+	hours: u8,
+	/// 9 bit day counter
+	days: u16,
+	halted: bool,
+	day_carry: bool,
+}
+
+impl RtcTime {
+	fn advance(&mut self, mut elapsed: u64) {
+		if self.halted {
+			return;
+		}
+
+		// Out of range values count up to the limit of their bits one second at a time.
+		// That takes at most 8 hours, after which the rest can be added in one go
+		while elapsed > 0 && !self.in_range() {
+			self.tick();
+			elapsed -= 1;
+		}
+		if elapsed == 0 {
+			return;
+		}
+
+		let seconds = self.seconds as u64 + elapsed;
+		let minutes = self.minutes as u64 + seconds / 60;
+		let hours = self.hours as u64 + minutes / 60;
+		let days = self.days as u64 + hours / 24;
+
+		self.seconds = (seconds % 60) as u8;
+		self.minutes = (minutes % 60) as u8;
+		self.hours = (hours % 24) as u8;
+		self.days = (days % 512) as u16;
+		if days >= 512 {
+			self.day_carry = true;
+		}
+	}
+
+	fn in_range(&self) -> bool {
+		self.seconds < 60 && self.minutes < 60 && self.hours < 24
+	}
+
+	/// Advances by a single second
+	fn tick(&mut self) {
+		if !RtcTime::increment(&mut self.seconds, 60, SECONDS_MASK)
+			|| !RtcTime::increment(&mut self.minutes, 60, MINUTES_MASK)
+			|| !RtcTime::increment(&mut self.hours, 24, HOURS_MASK) {
+			return;
+		}
+
+		self.days = (self.days + 1) % 512;
+		if self.days == 0 {
+			self.day_carry = true;
+		}
+	}
+
+	/// Counts a field up by one. Only the normal rollover carries into the next field,
+	/// values past it wrap to 0 at the width of the register without carrying
+	fn increment(value: &mut u8, rollover: u8, mask: u8) -> bool {
+		if *value == rollover - 1 {
+			*value = 0;
+			return true;
+		}
+
+		*value = value.wrapping_add(1) & mask;
+		false
+	}
+
+	fn registers(&self) -> [u8; 5] {
+		let mut days_high = (self.days >> 8) as u8;
+		if self.halted {
+			days_high |= HALT_MASK;
+		}
+		if self.day_carry {
+			days_high |= DAY_CARRY_MASK;
+		}
+
+		[self.seconds, self.minutes, self.hours, self.days as u8, days_high]
+	}
+}
+
+/// The MBC3 real-time clock. Reads only ever see the registers as they were when last latched
+pub struct Rtc {
+	clock: Box<dyn Clock>,
+	time: RtcTime,
+	/// When `time` was last brought up to date
+	last_update: u64,
+	latched: [u8; 5],
+}
+
+impl Rtc {
+	pub fn new(clock: Box<dyn Clock>) -> Self {
+		let last_update = clock.now();
+		Self {
+			clock,
+			time: RtcTime::default(),
+			last_update,
+			latched: [0; 5],
+		}
+	}
+
+	pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+		self.update();
+		self.last_update = clock.now();
+		self.clock = clock;
+	}
+
+	pub fn is_register(register: u8) -> bool {
+		(SECONDS_REGISTER..=DAYS_HIGH_REGISTER).contains(&register)
+	}
+
+	/// Copies the current time into the registers the CPU can read
+	pub fn latch(&mut self) {
+		self.update();
+		self.latched = self.time.registers();
+	}
+
+	pub fn read(&self, register: u8) -> u8 {
+		self.latched[(register - SECONDS_REGISTER) as usize]
+	}
+
+	pub fn write(&mut self, register: u8, value: u8) {
+		self.update();
+		let time = &mut self.time;
+		match register {
+			SECONDS_REGISTER => time.seconds = value & SECONDS_MASK,
+			MINUTES_REGISTER => time.minutes = value & MINUTES_MASK,
+			HOURS_REGISTER => time.hours = value & HOURS_MASK,
+			DAYS_LOW_REGISTER => time.days = (time.days & 0x100) | value as u16,
+			_ => {
+				time.days = (time.days & 0xFF) | ((value as u16 & 1) << 8);
+				time.halted = value & HALT_MASK != 0;
+				time.day_carry = value & DAY_CARRY_MASK != 0;
+			}
+		}
+	}
+
+	/// Current and latched registers followed by the time they were saved at
+	pub fn save_state(&self) -> Vec<u8> {
+		let now = self.clock.now();
+		let mut time = self.time;
+		time.advance(now.saturating_sub(self.last_update));
+
+		let mut state = Vec::with_capacity(RTC_STATE_SIZE);
+		for register in time.registers().iter().chain(self.latched.iter()) {
+			state.extend_from_slice(&(*register as u32).to_le_bytes());
+		}
+		state.extend_from_slice(&now.to_le_bytes());
+
+		state
+	}
+
+	/// Restores a saved state, catching up on the time that passed since it was saved
+	pub fn load_state(&mut self, state: &[u8]) {
+		if state.len() < RTC_STATE_SIZE {
+			return;
+		}
+
+		let register = |i: usize| state[i * 4];
+		for (i, register_address) in (SECONDS_REGISTER..=DAYS_HIGH_REGISTER).enumerate() {
+			self.write(register_address, register(i));
+			self.latched[i] = register(i + 5);
+		}
+
+		self.last_update = u64::from_le_bytes(state[40..48].try_into().unwrap());
+		self.update();
+	}
+
+	fn update(&mut self) {
+		let now = self.clock.now();
+		self.time.advance(now.saturating_sub(self.last_update));
+		self.last_update = now;
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+	use super::*;
+	use std::sync::Arc;
+	use std::sync::atomic::{AtomicU64, Ordering};
+
+	/// A clock that only moves when told to
+	#[derive(Clone, Default)]
+	pub(crate) struct FakeClock(pub(crate) Arc<AtomicU64>);
+
+	impl FakeClock {
+		pub(crate) fn advance(&self, seconds: u64) {
+			self.0.fetch_add(seconds, Ordering::SeqCst);
+		}
+	}
+
+	impl Clock for FakeClock {
+		fn now(&self) -> u64 {
+			self.0.load(Ordering::SeqCst)
+		}
+	}
+
+	#[test]
+	fn test_rtc() {
+		let clock = FakeClock::default();
+		let mut rtc = Rtc::new(Box::new(clock.clone()));
+
+		clock.advance(60 * 60 * 25 + 61);
+		assert_eq!(rtc.read(SECONDS_REGISTER), 0, "Nothing is visible until latched");
+		rtc.latch();
+		assert_eq!(rtc.read(SECONDS_REGISTER), 1);
+		assert_eq!(rtc.read(MINUTES_REGISTER), 1);
+		assert_eq!(rtc.read(HOURS_REGISTER), 1);
+		assert_eq!(rtc.read(DAYS_LOW_REGISTER), 1);
+
+		// Halting stops the clock
+		rtc.write(DAYS_HIGH_REGISTER, HALT_MASK);
+		clock.advance(100);
+		rtc.latch();
+		assert_eq!(rtc.read(SECONDS_REGISTER), 1);
+		assert_eq!(rtc.read(DAYS_HIGH_REGISTER), HALT_MASK);
+
+		// Set the time while halted, then let it run over the 9 bit day counter
+		rtc.write(DAYS_LOW_REGISTER, 0xFF);
+		rtc.write(DAYS_HIGH_REGISTER, 0x01);
+		rtc.write(HOURS_REGISTER, 23);
+		rtc.write(MINUTES_REGISTER, 59);
+		rtc.write(SECONDS_REGISTER, 59);
+		clock.advance(1);
+		rtc.latch();
+		assert_eq!(rtc.read(DAYS_LOW_REGISTER), 0);
+		assert_eq!(rtc.read(DAYS_HIGH_REGISTER), DAY_CARRY_MASK, "The carry stays set until cleared");
+		assert_eq!(rtc.read(HOURS_REGISTER), 0);
+	}
+
+	#[test]
+	fn test_rtc_out_of_range() {
+		let clock = FakeClock::default();
+		let mut rtc = Rtc::new(Box::new(clock.clone()));
+
+		// Seconds count up to 63 and wrap to 0 without carrying into the minutes
+		rtc.write(SECONDS_REGISTER, 62);
+		clock.advance(1);
+		rtc.latch();
+		assert_eq!(rtc.read(SECONDS_REGISTER), 63);
+		clock.advance(1);
+		rtc.latch();
+		assert_eq!(rtc.read(SECONDS_REGISTER), 0);
+		assert_eq!(rtc.read(MINUTES_REGISTER), 0);
+		clock.advance(60);
+		rtc.latch();
+		assert_eq!(rtc.read(SECONDS_REGISTER), 0);
+		assert_eq!(rtc.read(MINUTES_REGISTER), 1);
+
+		// Hours count up to 31, then carry on normally
+		rtc.write(HOURS_REGISTER, 30);
+		rtc.write(MINUTES_REGISTER, 59);
+		rtc.write(SECONDS_REGISTER, 59);
+		clock.advance(2 * 60 * 60 + 1);
+		rtc.latch();
+		assert_eq!(rtc.read(HOURS_REGISTER), 1);
+		assert_eq!(rtc.read(MINUTES_REGISTER), 0);
+		assert_eq!(rtc.read(SECONDS_REGISTER), 0);
+		assert_eq!(rtc.read(DAYS_LOW_REGISTER), 0, "Wrapping from 31 doesn't count a day");
+	}
+
+	#[test]
+	fn test_rtc_state() {
+		let clock = FakeClock::default();
+		let mut rtc = Rtc::new(Box::new(clock.clone()));
+		clock.advance(90);
+		rtc.latch();
+		let state = rtc.save_state();
+		assert_eq!(state.len(), RTC_STATE_SIZE);
+
+		// Time passes while the emulator isn't running
+		clock.advance(60);
+		let mut rtc = Rtc::new(Box::new(clock.clone()));
+		rtc.load_state(&state);
+		assert_eq!(rtc.read(SECONDS_REGISTER), 30, "The latched registers are restored as they were");
+		assert_eq!(rtc.read(MINUTES_REGISTER), 1);
+
+		rtc.latch();
+		assert_eq!(rtc.read(SECONDS_REGISTER), 30);
+		assert_eq!(rtc.read(MINUTES_REGISTER), 2);
+	}
+}
@@ -18,6 +18,16 @@ impl Color {
 }
 
 impl Color {
+	/// The greens of the original LCD
+	pub fn to_lcd_rgba(&self) -> [u8; 4] {
+		match self {
+			Color::White => [224, 248, 208, 255],
+			Color::LightGray => [136, 192, 112, 255],
+			Color::DarkGray => [52, 104, 86, 255],
+			Color::Black => [8, 24, 32, 255],
+		}
+	}
+
 	pub fn from_bits(v: u8) -> Color {
 		match v {
 			0 => Color::White,
@@ -29,14 +39,26 @@ impl Color {
 	}
 }
 
-struct Palette {
+pub(crate) struct Palette {
 	id_zero: Color,
 	id_one: Color,
 	id_two: Color,
 	id_three: Color,
 }
 
-fn get_palette(palette_register: u8) -> Palette {
+impl Palette {
+	/// The shade for a 2 bit color ID
+	pub(crate) fn color(&self, color_id: u8) -> Color {
+		match color_id & 0b11 {
+			0 => self.id_zero,
+			1 => self.id_one,
+			2 => self.id_two,
+			_ => self.id_three,
+		}
+	}
+}
+
+pub(crate) fn get_palette(palette_register: u8) -> Palette {
 	Palette {
 		id_zero:Color::from_bits(palette_register & 0b11),
 		id_one: Color::from_bits((palette_register >> 2) & 0b11),
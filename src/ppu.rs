@@ -1,7 +1,8 @@
 use std::collections::VecDeque;
 use crate::cpu::instruction::MCycles;
 use crate::ram::{Interrupt, Ram};
-use crate::lcd::{LYC_ADDRESS, LCDControl, LY_ADDRESS, STAT_ADDRESS};
+use crate::lcd::{Framebuffer, LYC_ADDRESS, LCDControl, LY_ADDRESS, STAT_ADDRESS, SCREEN_HEIGHT, SCREEN_WIDTH, SCX_ADDRESS, SCY_ADDRESS, BGP_ADDRESS, OBP0_ADDRESS, OBP1_ADDRESS, WX_ADDRESS, WY_ADDRESS};
+use crate::palette::{get_palette, Color};
 
 /// Makes graphics. Has 12 registers
 /// 160x144 pixels
@@ -48,9 +49,49 @@ const TOTAL_SCAN_LINES: u8 = 154;
 const SCANLINE_END_DOT: u16 = 456;
 const OAM_SCAN_END_DOT: u16 = 80;
 const INTERRUPT_SCANLINE: u8 = 144;
+/// WX is the window's left edge plus 7, so anything past this is off screen
+const WINDOW_MAX_WX: u8 = 166;
+const WINDOW_X_OFFSET: usize = 7;
+const OAM_START_ADDRESS: u16 = 0xFE00;
+const OBJECT_COUNT: u16 = 40;
+const MAX_OBJECTS_PER_LINE: usize = 10;
+/// Object coordinates are offset so objects can be partially off the top and left of the screen
+const OBJECT_Y_OFFSET: u8 = 16;
+const OBJECT_X_OFFSET: usize = 8;
 
 type Tile<'a> = &'a [[u8; 8]; 8];
 
+/// An OAM entry picked up by the OAM scan
+#[derive(Copy, Clone, Debug)]
+struct Object {
+	y: u8,
+	x: u8,
+	tile_index: u8,
+	attributes: u8,
+}
+
+impl Object {
+	fn bg_over_obj(&self) -> bool {
+		(self.attributes & 0b1000_0000) != 0
+	}
+
+	fn y_flip(&self) -> bool {
+		(self.attributes & 0b0100_0000) != 0
+	}
+
+	fn x_flip(&self) -> bool {
+		(self.attributes & 0b0010_0000) != 0
+	}
+
+	fn palette_address(&self) -> u16 {
+		if (self.attributes & 0b0001_0000) != 0 {
+			OBP1_ADDRESS
+		} else {
+			OBP0_ADDRESS
+		}
+	}
+}
+
 #[derive(Copy, Clone, Debug)]
 enum PPUMode {
 	OAMScan=2,
@@ -68,6 +109,23 @@ pub struct PPU {
 	object_fifo: [u8; 16],
 
 	work_stack: VecDeque<(fn() -> (), fn() -> ())>,
+
+	/// Every enabled STAT source ORed together. The interrupt only fires when this goes from low to high
+	stat_line: bool,
+	lcd_enabled: bool,
+	/// Dots since the last blank frame while the LCD is off
+	lcd_off_dots: usize,
+
+	/// Set once LY has matched WY this frame. The window can only be drawn from then on
+	window_y_triggered: bool,
+	/// The window row to draw next. Only advances on lines the window was actually drawn on
+	window_line: u8,
+	/// Objects on the current line, in OAM order
+	line_objects: Vec<Object>,
+
+	framebuffer: Box<Framebuffer>,
+	/// Set once the last line of a frame is drawn, until the frame is taken
+	frame_ready: bool,
 }
 
 type DotsTaken = u8;
@@ -84,11 +142,34 @@ impl PPU {
 			object_fifo: [0; 16],
 
 			work_stack: VecDeque::new(),
+
+			stat_line: false,
+			lcd_enabled: true,
+			lcd_off_dots: 0,
+
+			window_y_triggered: false,
+			window_line: 0,
+			line_objects: Vec::with_capacity(MAX_OBJECTS_PER_LINE),
+
+			framebuffer: Box::new([[Color::White; SCREEN_WIDTH]; SCREEN_HEIGHT]),
+			frame_ready: false,
 		}
 	}
 
 	pub fn tick(&mut self, m_cycles: MCycles, ram: &mut Ram) {
 		let dots = DOTS_PER_M_CYCLE * m_cycles;
+		if !ram.lcd_enabled() {
+			self.turn_off(ram);
+
+			// The screen stays blank, but frontends still get a frame at the usual rate
+			self.lcd_off_dots += dots;
+			if self.lcd_off_dots >= DOTS_PER_60_FPS_FRAME {
+				self.lcd_off_dots -= DOTS_PER_60_FPS_FRAME;
+				self.frame_ready = true;
+			}
+			return;
+		}
+		self.lcd_enabled = true;
 
 		for _ in 0..dots {
 			self.do_dot(ram);
@@ -120,6 +201,8 @@ impl PPU {
 
 		if self.current_scanline_dot == OAM_SCAN_END_DOT && self.current_scanline < INTERRUPT_SCANLINE {
 			self.mode = PPUMode::DrawingPixels;
+			self.handle_oam_scan(ram);
+			self.render_scanline(ram);
 		}
 
 		if self.current_scanline_dot == 252 && self.current_scanline < INTERRUPT_SCANLINE {
@@ -130,6 +213,9 @@ impl PPU {
 		if self.current_scanline == INTERRUPT_SCANLINE && self.current_scanline_dot == 0 {
 			self.mode = PPUMode::VerticalBlank;
 			ram.request_interrupt(Interrupt::VBlank);
+			self.frame_ready = true;
+			self.window_y_triggered = false;
+			self.window_line = 0;
 		}
 
 		if self.current_scanline == TOTAL_SCAN_LINES {
@@ -142,52 +228,217 @@ impl PPU {
 		self.handle_stat(ram);
 	}
 
+	/// With the LCD off the PPU sits at the start of the frame in mode 0, leaving VRAM and OAM open.
+	/// Once it's turned back on, the first line starts in mode 0 instead of scanning OAM
+	fn turn_off(&mut self, ram: &mut Ram) {
+		if !self.lcd_enabled {
+			return;
+		}
+
+		self.lcd_enabled = false;
+		self.lcd_off_dots = 0;
+		*self.framebuffer = [[Color::White; SCREEN_WIDTH]; SCREEN_HEIGHT];
+		self.frame_ready = true;
+		self.window_y_triggered = false;
+		self.window_line = 0;
+		self.current_scanline = 0;
+		self.current_scanline_dot = 0;
+		self.mode = PPUMode::HorizontalBlank;
+		self.stat_line = false;
+		PPU::handle_lcd_update(ram, 0);
+		let stat = ram.unblocked_read(STAT_ADDRESS);
+		ram.unblocked_write(STAT_ADDRESS, stat & 0b1111_1100);
+	}
+
+	/// The finished frame, once per frame
+	pub fn take_frame(&mut self) -> Option<Box<Framebuffer>> {
+		if !std::mem::take(&mut self.frame_ready) {
+			return None;
+		}
+
+		Some(self.framebuffer.clone())
+	}
+
+	/// Draws the whole line at the start of mode 3, using the registers as they are at that point
+	fn render_scanline(&mut self, ram: &Ram) {
+		let line = self.current_scanline as usize;
+		if self.current_scanline == ram.unblocked_read(WY_ADDRESS) {
+			self.window_y_triggered = true;
+		}
+
+		// With the background and window off, objects are drawn over color ID 0
+		let mut color_ids = [0; SCREEN_WIDTH];
+		if ram.bg_and_window_enabled() {
+			self.render_background(ram, &mut color_ids);
+			self.render_window(ram, &mut color_ids);
+		}
+
+		let palette = get_palette(ram.unblocked_read(BGP_ADDRESS));
+		for (pixel, color_id) in self.framebuffer[line].iter_mut().zip(color_ids) {
+			*pixel = palette.color(color_id);
+		}
+
+		if ram.obj_enabled() {
+			self.render_objects(ram, &color_ids);
+		}
+	}
+
+	fn render_background(&self, ram: &Ram, color_ids: &mut [u8; SCREEN_WIDTH]) {
+		let scx = ram.unblocked_read(SCX_ADDRESS);
+		let scy = ram.unblocked_read(SCY_ADDRESS);
+		let tile_map = PPU::get_tile_map_start(ram) as u16;
+
+		let y = self.current_scanline.wrapping_add(scy);
+		for (x, color_id) in color_ids.iter_mut().enumerate() {
+			*color_id = PPU::tile_map_color_id(ram, tile_map, (x as u8).wrapping_add(scx), y);
+		}
+	}
+
+	/// Draws the window over the background from WX - 7 to the right edge of the screen.
+	/// A WX below 7 cuts off the leftmost columns of the window instead
+	fn render_window(&mut self, ram: &Ram, color_ids: &mut [u8; SCREEN_WIDTH]) {
+		let wx = ram.unblocked_read(WX_ADDRESS);
+		if !ram.window_enabled() || !self.window_y_triggered || wx > WINDOW_MAX_WX {
+			return;
+		}
+
+		let tile_map = PPU::get_window_tile_map_start(ram);
+		let window_start = (wx as usize).saturating_sub(WINDOW_X_OFFSET);
+		for (x, color_id) in color_ids.iter_mut().enumerate().skip(window_start) {
+			let window_x = (x + WINDOW_X_OFFSET - wx as usize) as u8;
+			*color_id = PPU::tile_map_color_id(ram, tile_map, window_x, self.window_line);
+		}
+
+		self.window_line += 1;
+	}
+
+	/// On DMG the object with the lowest X wins where objects overlap, then the one earliest in OAM.
+	/// Only the winning object's BG-over-OBJ flag matters, so a hidden pixel also hides any object below it
+	fn render_objects(&mut self, ram: &Ram, bg_color_ids: &[u8; SCREEN_WIDTH]) {
+		let height = PPU::object_height(ram);
+		let mut objects = self.line_objects.clone();
+		objects.sort_by_key(|object| object.x);
+
+		let line = self.current_scanline as usize;
+		let mut drawn = [false; SCREEN_WIDTH];
+		for object in objects {
+			let mut row = self.current_scanline + OBJECT_Y_OFFSET - object.y;
+			if object.y_flip() {
+				row = height - 1 - row;
+			}
+
+			// 8x16 objects ignore the lowest bit of the tile index
+			let tile_index = if height == 16 { object.tile_index & 0xFE } else { object.tile_index };
+			let row_address = 0x8000 + tile_index as u16 * 16 + row as u16 * 2;
+			let low = ram.unblocked_read(row_address);
+			let high = ram.unblocked_read(row_address + 1);
+			let palette = get_palette(ram.unblocked_read(object.palette_address()));
+
+			for column in 0..8 {
+				let Some(x) = (object.x as usize + column).checked_sub(OBJECT_X_OFFSET).filter(|x| *x < SCREEN_WIDTH) else {
+					continue;
+				};
+
+				let bit = if object.x_flip() { column } else { 7 - column };
+				let color_id = (((high >> bit) & 1) << 1) | ((low >> bit) & 1);
+				// Color ID 0 is transparent and lets lower priority objects through
+				if drawn[x] || color_id == 0 {
+					continue;
+				}
+
+				drawn[x] = true;
+				if !object.bg_over_obj() || bg_color_ids[x] == 0 {
+					self.framebuffer[line][x] = palette.color(color_id);
+				}
+			}
+		}
+	}
+
+	fn object_height(ram: &Ram) -> u8 {
+		if ram.obj_size_control() {
+			16
+		} else {
+			8
+		}
+	}
+
+	/// Color ID of the pixel at x, y within the 256x256 pixel tile map
+	fn tile_map_color_id(ram: &Ram, tile_map: u16, x: u8, y: u8) -> u8 {
+		let tile_index = ram.unblocked_read(tile_map + (y as u16 / 8) * 32 + x as u16 / 8);
+		let row_address = ram.bg_and_window_tile_address(tile_index) + (y as u16 % 8) * 2;
+		let low = ram.unblocked_read(row_address);
+		let high = ram.unblocked_read(row_address + 1);
+
+		let bit = 7 - (x % 8);
+		(((high >> bit) & 1) << 1) | ((low >> bit) & 1)
+	}
+
 	fn handle_lcd_update(ram: &mut Ram, current_scanline: u8) {
 		ram.update_ly(current_scanline);
 	}
 
-	fn handle_stat(&self, ram: &mut Ram) {
+	fn handle_stat(&mut self, ram: &mut Ram) {
 		let ly = ram.unblocked_read(LY_ADDRESS);
 		let lyc = ram.unblocked_read(LYC_ADDRESS);
-		let prev_lyc_equals_ly = (ram.unblocked_read(STAT_ADDRESS) & 0b0000_0100) >> 2;
 		let prev_status = ram.unblocked_read(STAT_ADDRESS);
-		let prev_mode = prev_status & 0b0000_0011;
 
 		let lyc_equals_ly_bit = ((ly == lyc) as u8) << 2;
 		let ppu_mode_mod = if !ram.lcd_enabled() { 0 } else { self.mode as u8 };
 		let mask = lyc_equals_ly_bit | ppu_mode_mod;
 		let res = (prev_status & 0b1111_1000) | mask;
-		ram.write(STAT_ADDRESS, res);
+		ram.unblocked_write(STAT_ADDRESS, res);
 
 		// Check interrupts
-		let mut stat_interrupt_line: bool = false;
-		if (prev_status & 0b0100_0000) > 0 && !(prev_lyc_equals_ly > 0) {
-			stat_interrupt_line |= lyc_equals_ly_bit > 0;
+		let mut stat_line = false;
+		if (prev_status & 0b0100_0000) > 0 {
+			stat_line |= lyc_equals_ly_bit > 0;
 		}
 
-		if (prev_status & 0b0010_0000) > 0 && (self.mode as u8) == PPUMode::OAMScan as u8 && prev_mode != self.mode as u8 {
-			stat_interrupt_line |= true;
+		// On DMG the mode 2 source also sees the first dot of VBlank
+		let vblank_start = self.current_scanline == INTERRUPT_SCANLINE && self.current_scanline_dot == 0;
+		if (prev_status & 0b0010_0000) > 0 && ((self.mode as u8) == PPUMode::OAMScan as u8 || vblank_start) {
+			stat_line |= true;
 		}
 
-		if (prev_status & 0b0001_0000) > 0 && (self.mode as u8) == PPUMode::VerticalBlank as u8 && prev_mode != self.mode as u8 {
-			stat_interrupt_line |= true;
+		if (prev_status & 0b0001_0000) > 0 && (self.mode as u8) == PPUMode::VerticalBlank as u8 {
+			stat_line |= true;
 		}
 
-		if (prev_status & 0b0000_1000) > 0 && (self.mode as u8) == PPUMode::HorizontalBlank as u8 && prev_mode != self.mode as u8 {
-			stat_interrupt_line |= true;
+		if (prev_status & 0b0000_1000) > 0 && (self.mode as u8) == PPUMode::HorizontalBlank as u8 {
+			stat_line |= true;
 		}
 
-		if stat_interrupt_line {
+		if stat_line && !self.stat_line {
 			ram.request_interrupt(Interrupt::Stat);
 		}
+		self.stat_line = stat_line;
 	}
 
-	fn handle_oam_scan(&mut self) {
-		// We don't need to split this up at all. We just need to do this once
+	/// Picks the first 10 objects in OAM that overlap the current line. Their X doesn't matter,
+	/// so objects off screen to the left or right still count towards the limit
+	fn handle_oam_scan(&mut self, ram: &Ram) {
+		self.line_objects.clear();
+		let height = PPU::object_height(ram);
+		let line = self.current_scanline + OBJECT_Y_OFFSET;
+
+		for index in 0..OBJECT_COUNT {
+			if self.line_objects.len() == MAX_OBJECTS_PER_LINE {
+				break;
+			}
+
+			let address = OAM_START_ADDRESS + index * 4;
+			let y = ram.ppu_read_oam(address);
+			if line < y || line >= y.saturating_add(height) {
+				continue;
+			}
 
-		// 	The Game Boy PPU can display up to 40 movable objects (or sprites), each 8×8 or 8×16 pixels.
-		// Because of a limitation of hardware, only ten objects can be displayed per scanline.
-		// Object tiles have the same format as BG tiles, but they are taken from tile blocks 0 and 1 located at $8000-8FFF and have unsigned numbering.
+			self.line_objects.push(Object {
+				y,
+				x: ram.ppu_read_oam(address + 1),
+				tile_index: ram.ppu_read_oam(address + 2),
+				attributes: ram.ppu_read_oam(address + 3),
+			});
+		}
 	}
 
 	fn handle_pixel_fetch(&mut self) {
@@ -198,7 +449,7 @@ impl PPU {
 
 	}
 
-	fn get_tile_map_start(&mut self, ram: &Ram) -> usize {
+	fn get_tile_map_start(ram: &Ram) -> usize {
 		if ram.bg_tile_map_control() {
 			0x9C00
 		} else {
@@ -207,6 +458,14 @@ impl PPU {
 
 	}
 
+	fn get_window_tile_map_start(ram: &Ram) -> u16 {
+		if ram.window_tile_map_control() {
+			0x9C00
+		} else {
+			0x9800
+		}
+	}
+
 	fn sleep(&mut self) -> DotsTaken {
 		// Do nothing
 		2
@@ -221,52 +480,283 @@ impl PPU {
 mod test {
 	use super::*;
 
+	#[test]
+	fn test_vblank_oam_interrupt() {
+		let mut ram = Ram::new();
+		ram.set_lcd_enabled(true);
+		ram.write(STAT_ADDRESS, 0b0010_0000);
+		ram.write(0xFFFF, 0xFF);
+		let mut ppu = PPU::new();
+
+		// The mode 2 source fires at the start of VBlank as well
+		ppu.tick(114 * 144 - 1, &mut ram);
+		ram.clear_interrupt(Interrupt::Stat);
+		ppu.tick(1, &mut ram);
+		assert_eq!(ram.pending_interrupt(), Some(Interrupt::VBlank));
+		ram.clear_interrupt(Interrupt::VBlank);
+		assert_eq!(ram.pending_interrupt(), Some(Interrupt::Stat));
+
+		// But only once
+		ram.clear_interrupt(Interrupt::Stat);
+		ppu.tick(113, &mut ram);
+		assert_eq!(ram.pending_interrupt(), None);
+	}
+
+	#[test]
+	fn test_lcd_off() {
+		let mut ram = Ram::new();
+		ram.set_lcd_enabled(true);
+		let mut ppu = PPU::new();
+
+		// Into the middle of drawing the second line
+		ppu.tick(114 + 30, &mut ram);
+		assert_eq!(ram.unblocked_read(LY_ADDRESS), 1);
+		assert_eq!(ram.unblocked_read(STAT_ADDRESS) & 0b11, PPUMode::DrawingPixels as u8);
+		assert_eq!(ram.read(0x8000), 0xFF, "VRAM is blocked while drawing");
+
+		ram.set_lcd_enabled(false);
+		ppu.tick(1, &mut ram);
+		assert_eq!(ram.unblocked_read(LY_ADDRESS), 0);
+		assert_eq!(ram.unblocked_read(STAT_ADDRESS) & 0b11, 0);
+		assert_eq!(ram.read(0x8000), 0x00, "Everything is accessible with the LCD off");
+
+		// The first line after turning the LCD on skips OAM scan
+		ram.set_lcd_enabled(true);
+		ppu.tick(1, &mut ram);
+		assert_eq!(ram.unblocked_read(STAT_ADDRESS) & 0b11, PPUMode::HorizontalBlank as u8);
+		ppu.tick(20, &mut ram);
+		assert_eq!(ram.unblocked_read(STAT_ADDRESS) & 0b11, PPUMode::DrawingPixels as u8);
+	}
+
+	#[test]
+	fn test_render_scanline() {
+		let mut ram = Ram::new();
+		ram.write(0xFF40, 0b1001_0001);
+		// BGP maps color IDs 1, 2 and 3 to black, white and dark gray
+		ram.write(BGP_ADDRESS, 0b1000_1100);
+
+		// Tile 1 is solid color ID 1 on even rows and color ID 2 on odd rows
+		for row in 0..8 {
+			let (low, high) = if row % 2 == 0 { (0xFF, 0x00) } else { (0x00, 0xFF) };
+			ram.write(0x8010 + row * 2, low);
+			ram.write(0x8011 + row * 2, high);
+		}
+		// Placed at tile column 2, tile row 1 of the map
+		ram.write(0x9800 + 32 + 2, 1);
+
+		// Scrolled so the tile starts at the top left of the screen, one line in
+		ram.write(SCX_ADDRESS, 16);
+		ram.write(SCY_ADDRESS, 9);
+
+		let mut ppu = PPU::new();
+		ppu.tick(114 * 144, &mut ram);
+		let frame = ppu.take_frame().expect("A frame is ready at VBlank");
+		assert!(ppu.take_frame().is_none(), "Each frame is only taken once");
+
+		assert_eq!(frame[0][0..8], [Color::White; 8], "Row 1 of the tile is color ID 2");
+		assert_eq!(frame[1][0..8], [Color::Black; 8], "Row 2 of the tile is color ID 1");
+		assert_eq!(frame[0][8], Color::White, "Color ID 0 is white");
+		assert_eq!(frame[7][0], Color::White);
+		assert_eq!(frame[7][8], Color::White);
+		assert_eq!(frame[10][0], Color::White, "Past the bottom of the tile");
+
+		// The tile map wraps around
+		ram.write(SCX_ADDRESS, 16 + 128);
+		ram.write(0x9800 + 32 + 2 + 16, 1);
+		ppu.tick(114 * 154, &mut ram);
+		let frame = ppu.take_frame().unwrap();
+		assert_eq!(frame[1][128..136], [Color::Black; 8]);
+		assert_eq!(frame[1][0..8], [Color::Black; 8]);
+	}
+
+	#[test]
+	fn test_window() {
+		let mut ram = Ram::new();
+		// Window enabled and using the 0x9C00 tile map
+		ram.write(0xFF40, 0b1111_0001);
+		ram.write(BGP_ADDRESS, 0b1110_0100);
+
+		// Tile 1 has color ID 3 in its top row and color ID 1 everywhere else
+		ram.write(0x8010, 0xFF);
+		ram.write(0x8011, 0xFF);
+		for row in 1..8 {
+			ram.write(0x8010 + row * 2, 0xFF);
+		}
+		for i in 0..32 * 32 {
+			ram.write(0x9C00 + i, 1);
+		}
+		ram.write(WY_ADDRESS, 2);
+		ram.write(WX_ADDRESS, 7 + 16);
+
+		let mut ppu = PPU::new();
+		let render_line = |ppu: &mut PPU, ram: &Ram, line: u8| {
+			ppu.current_scanline = line;
+			ppu.render_scanline(ram);
+			ppu.framebuffer[line as usize]
+		};
+
+		assert_eq!(render_line(&mut ppu, &ram, 1), [Color::White; SCREEN_WIDTH], "Above WY");
+		let line = render_line(&mut ppu, &ram, 2);
+		assert_eq!(line[15], Color::White, "Left of WX - 7");
+		assert_eq!(line[16..], [Color::Black; SCREEN_WIDTH - 16], "The window's first row");
+
+		// Disabling the window for a line doesn't advance the window's line counter
+		ram.write(0xFF40, 0b1101_0001);
+		assert_eq!(render_line(&mut ppu, &ram, 3), [Color::White; SCREEN_WIDTH]);
+		ram.write(0xFF40, 0b1111_0001);
+		assert_eq!(render_line(&mut ppu, &ram, 4)[16], Color::LightGray, "The window's second row");
+
+		// A WX below 7 cuts off the left of the window
+		ram.write(0x9C00, 0);
+		ram.write(WX_ADDRESS, 3);
+		let line = render_line(&mut ppu, &ram, 5);
+		assert_eq!(line[0..4], [Color::White; 4]);
+		assert_eq!(line[4], Color::LightGray);
+
+		// Off screen to the right
+		ram.write(0x9C00, 1);
+		ram.write(WX_ADDRESS, WINDOW_MAX_WX + 1);
+		assert_eq!(render_line(&mut ppu, &ram, 6), [Color::White; SCREEN_WIDTH]);
+		ram.write(WX_ADDRESS, WINDOW_MAX_WX);
+		assert_eq!(render_line(&mut ppu, &ram, 7)[SCREEN_WIDTH - 1], Color::LightGray);
+	}
+
+	#[test]
+	fn test_objects() {
+		let mut ram = Ram::new();
+		// Objects enabled, background off
+		ram.write(0xFF40, 0b1001_0010);
+		ram.write(OBP0_ADDRESS, 0b1110_0100);
+		ram.write(OBP1_ADDRESS, 0b1001_0000);
+
+		// Tile 2 has color ID 1 in the left half of its top row and color ID 3 everywhere else.
+		// Tile 3 is solid color ID 2
+		ram.write(0x8020, 0xFF);
+		ram.write(0x8021, 0x0F);
+		for row in 1..8 {
+			ram.write(0x8020 + row * 2, 0xFF);
+			ram.write(0x8021 + row * 2, 0xFF);
+			ram.write(0x8030 + row * 2, 0x00);
+			ram.write(0x8031 + row * 2, 0xFF);
+		}
+		ram.write(0x8031, 0xFF);
+
+		let set_object = |ram: &mut Ram, index: u16, y: u8, x: u8, tile_index: u8, attributes: u8| {
+			for (offset, value) in [y, x, tile_index, attributes].into_iter().enumerate() {
+				ram.write(OAM_START_ADDRESS + index * 4 + offset as u16, value);
+			}
+		};
+		let render_line = |ppu: &mut PPU, ram: &Ram, line: u8| {
+			ppu.current_scanline = line;
+			ppu.handle_oam_scan(ram);
+			ppu.render_scanline(ram);
+			ppu.framebuffer[line as usize]
+		};
+
+		// Top left corner of the screen, then flipped both ways
+		let mut ppu = PPU::new();
+		set_object(&mut ram, 0, 16, 8, 2, 0);
+		let line = render_line(&mut ppu, &ram, 0);
+		assert_eq!(line[0..8], [[Color::LightGray; 4], [Color::Black; 4]].concat()[..]);
+		assert_eq!(line[8], Color::White);
+		set_object(&mut ram, 0, 16, 8, 2, 0b0110_0000);
+		assert_eq!(render_line(&mut ppu, &ram, 7)[0..8], [[Color::Black; 4], [Color::LightGray; 4]].concat()[..]);
+
+		// The lower X wins, even when it's later in OAM. OBP1 maps color ID 2 to light gray
+		set_object(&mut ram, 0, 16, 12, 3, 0);
+		set_object(&mut ram, 1, 16, 8, 3, 0b0001_0000);
+		let line = render_line(&mut ppu, &ram, 1);
+		assert_eq!(line[0..8], [Color::LightGray; 8]);
+		assert_eq!(line[8..12], [Color::DarkGray; 4]);
+
+		// With equal X the first in OAM wins, but its transparent pixels show the other object
+		set_object(&mut ram, 0, 16, 8, 2, 0);
+		set_object(&mut ram, 1, 16, 8, 3, 0b0001_0000);
+		assert_eq!(render_line(&mut ppu, &ram, 0)[4..8], [Color::Black; 4]);
+		ram.write(0x8020, 0x0F);
+		assert_eq!(render_line(&mut ppu, &ram, 0)[0..8], [[Color::LightGray; 4], [Color::Black; 4]].concat()[..]);
+
+		// BG-over-OBJ only hides the object over background color IDs 1 to 3
+		ram.write(0xFF40, 0b1001_0011);
+		ram.write(BGP_ADDRESS, 0b1110_0100);
+		ram.write(0x9800, 1);
+		ram.write(0x8012, 0xF0);
+		set_object(&mut ram, 0, 16, 8, 2, 0b1000_0000);
+		set_object(&mut ram, 1, 0, 0, 0, 0);
+		let line = render_line(&mut ppu, &ram, 1);
+		assert_eq!(line[0..4], [Color::LightGray; 4], "Hidden behind background color ID 1");
+		assert_eq!(line[4..8], [Color::Black; 4], "Background color ID 0 is behind every object");
+
+		// Tall objects span two tiles
+		ram.write(0xFF40, 0b1001_0110);
+		set_object(&mut ram, 0, 16, 8, 3, 0);
+		assert_eq!(render_line(&mut ppu, &ram, 1)[0], Color::Black, "Tile 2 on top");
+		assert_eq!(render_line(&mut ppu, &ram, 9)[0], Color::DarkGray, "Tile 3 below");
+		assert_eq!(render_line(&mut ppu, &ram, 16)[0], Color::White);
+
+		// Only the first 10 objects in OAM are drawn, whether or not they're on screen
+		ram.write(0xFF40, 0b1001_0010);
+		for index in 0..10 {
+			set_object(&mut ram, index, 32, 0, 3, 0);
+		}
+		set_object(&mut ram, 10, 32, 8, 3, 0);
+		assert_eq!(render_line(&mut ppu, &ram, 16)[0], Color::White);
+		set_object(&mut ram, 9, 0, 0, 3, 0);
+		assert_eq!(render_line(&mut ppu, &ram, 16)[0], Color::DarkGray);
+
+		ram.write(0xFF40, 0b1001_0000);
+		assert_eq!(render_line(&mut ppu, &ram, 16)[0], Color::White, "Objects are disabled");
+	}
+
 	#[test]
 	fn test_handle_stat() {
 		let mut ram = Ram::new();
-		ram.write(LY_ADDRESS, 5);
+		ram.update_ly(5);
 		let mut ppu = PPU::new();
 		ram.set_lcd_enabled(true);
 		ram.write(0xFFFF, 0xFF);
-		assert_eq!(ram.unblocked_read(STAT_ADDRESS), 0b0000_0000);
+		assert_eq!(ram.unblocked_read(STAT_ADDRESS), 0b1000_0000);
 
 		// Test modes
 		ppu.mode = PPUMode::HorizontalBlank;
 		ppu.handle_stat(&mut ram);
-		assert_eq!(ram.unblocked_read(STAT_ADDRESS), 0b0000_0000);
+		assert_eq!(ram.unblocked_read(STAT_ADDRESS), 0b1000_0000);
 
 		ppu.mode = PPUMode::OAMScan;
 		ppu.handle_stat(&mut ram);
-		assert_eq!(ram.unblocked_read(STAT_ADDRESS), 0b0000_0010);
+		assert_eq!(ram.unblocked_read(STAT_ADDRESS), 0b1000_0010);
 
 		ppu.mode = PPUMode::VerticalBlank;
 		ppu.handle_stat(&mut ram);
-		assert_eq!(ram.unblocked_read(STAT_ADDRESS), 0b0000_0001);
+		assert_eq!(ram.unblocked_read(STAT_ADDRESS), 0b1000_0001);
 
 		ppu.mode = PPUMode::DrawingPixels;
 		ppu.handle_stat(&mut ram);
-		assert_eq!(ram.unblocked_read(STAT_ADDRESS), 0b0000_0011);
+		assert_eq!(ram.unblocked_read(STAT_ADDRESS), 0b1000_0011);
 
 		// Set LY and LYC to be equal
 		ram.write(LYC_ADDRESS, 5);
 		ppu.handle_stat(&mut ram);
-		assert_eq!(ram.unblocked_read(STAT_ADDRESS), 0b0000_0111);
+		assert_eq!(ram.unblocked_read(STAT_ADDRESS), 0b1000_0111);
 
 		// Enable LY=LYC interrupt
 		ram.write(STAT_ADDRESS, 0b0100_0000);
 		ppu.handle_stat(&mut ram);
-		assert_eq!(ram.unblocked_read(STAT_ADDRESS), 0b0100_0111);
+		assert_eq!(ram.unblocked_read(STAT_ADDRESS), 0b1100_0111);
 		assert_eq!(ram.pending_interrupt(), Some(Interrupt::Stat));
 
 		ram.clear_interrupt(Interrupt::Stat);
 		assert_eq!(ram.pending_interrupt(), None);
 
-		// Test mode 2 interrupt
+		// Test mode 2 interrupt. The line has to drop first, or the interrupt is blocked
 		ram.write(LYC_ADDRESS, 2); // Set LYC to something else
 		ram.write(STAT_ADDRESS, 0b0010_0000);
+		ppu.mode = PPUMode::DrawingPixels;
+		ppu.handle_stat(&mut ram);
+		assert_eq!(ram.pending_interrupt(), None);
 		ppu.mode = PPUMode::OAMScan;
 		ppu.handle_stat(&mut ram);
-		assert_eq!(ram.unblocked_read(STAT_ADDRESS), 0b0010_0010);
+		assert_eq!(ram.unblocked_read(STAT_ADDRESS), 0b1010_0010);
 		assert_eq!(ram.pending_interrupt(), Some(Interrupt::Stat));
 
 		ram.clear_interrupt(Interrupt::Stat);
@@ -277,7 +767,7 @@ mod test {
 		ram.write(STAT_ADDRESS, 0b1111_0001);
 		ppu.mode = PPUMode::VerticalBlank;
 		ppu.handle_stat(&mut ram);
-		assert_eq!(ram.unblocked_read(STAT_ADDRESS), 0b01111_0001);
+		assert_eq!(ram.unblocked_read(STAT_ADDRESS), 0b1111_0001);
 		assert_eq!(ram.pending_interrupt(), None);
 	}
 }
\ No newline at end of file
@@ -1,5 +1,15 @@
 
+use crate::input::{joypad_register, JOYPAD_ADDRESS};
+use crate::io_registers::{masked_write, register_mask};
+use crate::lcd::STAT_ADDRESS;
+use crate::mbc::rtc::Clock;
+use crate::mbc::{create_mbc, MemoryBankController, RomOnly, ROM_BANK_SIZE};
+use crate::rom::{Cartridge, CartridgeError};
+
 const TWO_TO_THE_16: usize = 65_536;
+const DIV_ADDRESS: u16 = 0xFF04;
+const TAC_ADDRESS: u16 = 0xFF07;
+const DMA_ADDRESS: u16 = 0xFF46;
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Debug)]
 pub enum Interrupt {
@@ -22,46 +32,167 @@ impl Interrupt {
 	}
 }
 
+/// The source address and value OAM DMA is moving during the current M-cycle
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DmaBus {
+	pub address: u16,
+	pub value: u8,
+}
+
 pub struct Ram {
 	data: [u8; TWO_TO_THE_16],
+	rom: Vec<u8>,
+	external_ram: Vec<u8>,
+	mbc: Box<dyn MemoryBankController>,
+	/// Every address is plain read/write memory. Only used by the JSON CPU tests
+	flat: bool,
+	has_battery: bool,
+	/// Set when battery backed RAM is written, until `mark_saved` is called
+	unsaved_changes: bool,
+	/// Called whenever the cartridge turns its rumble motor on or off
+	rumble_callback: Option<Box<dyn FnMut(bool) + Send>>,
+	/// Set when the CPU writes 0xFF46, until the DMA picks the request up
 	dma_requested: bool,
+	dma_bus: Option<DmaBus>,
+	/// Bit n is set when the CPU writes 0xFF04 + n, until the timer picks the writes up
+	timer_writes: u8,
+	pressed_buttons: u8,
 }
 
 impl Ram {
 	pub fn new() -> Self {
 		Self {
 			data: [0; TWO_TO_THE_16],
+			rom: vec![0; 2 * ROM_BANK_SIZE],
+			external_ram: vec![],
+			mbc: Box::new(RomOnly),
+			flat: false,
+			has_battery: false,
+			unsaved_changes: false,
+			rumble_callback: None,
 			dma_requested: false,
+			dma_bus: None,
+			timer_writes: 0,
+			pressed_buttons: 0,
+		}
+	}
+
+	/// Memory without any of the memory map, so every address can be written and read back
+	pub fn new_flat() -> Self {
+		Self {
+			flat: true,
+			..Self::new()
 		}
 	}
 
 	pub fn read(&self, address: u16) -> u8 {
-		if self.dma_requested && address == 0xFF46 {
-			// During a DMA request, reading the DMA register returns 0xFF
-			return 0xFF;
+		if let Some(dma_bus) = self.dma_bus {
+			// OAM is unreadable and the bus DMA is using only carries the byte being transferred
+			if is_oam(address) {
+				return 0xFF;
+			}
+			if same_bus(address, dma_bus.address) {
+				return dma_bus.value;
+			}
 		}
 
-		// During a DMA request, reading outside HRAM returns 0xFF
-		if self.dma_requested && (address < 0xFF80 || address > 0xFFFE) {
+		if self.blocked_by_ppu(address) {
 			return 0xFF;
 		}
 
-		self.data[address as usize]
+		self.unblocked_read(address)
+	}
+
+	/// The PPU keeps the CPU out of OAM while scanning or drawing, and out of VRAM while drawing.
+	/// The mode in STAT is always 0 while the LCD is off, so nothing is blocked then
+	fn blocked_by_ppu(&self, address: u16) -> bool {
+		if self.flat {
+			return false;
+		}
+
+		let mode = self.data[STAT_ADDRESS as usize] & 0b0000_0011;
+		match address {
+			0x8000..=0x9FFF => mode == 3,
+			// The unusable region reads 0xFF instead of 0x00 while OAM is blocked
+			0xFE00..=0xFEFF => mode == 2 || mode == 3,
+			_ => false,
+		}
 	}
 
 	pub fn unblocked_read(&self, address: u16) -> u8 {
-		self.data[address as usize]
+		if self.flat {
+			return self.data[address as usize];
+		}
+
+		match address {
+			0x0000..=0x7FFF => self.rom[self.mbc.rom_offset(address) % self.rom.len()],
+			0xA000..=0xBFFF => self.mbc.read_ram(&self.external_ram, address),
+			// Echo RAM mirrors WRAM
+			0xE000..=0xFDFF => self.data[(address - 0x2000) as usize],
+			// The unusable region reads as 0 on DMG
+			0xFEA0..=0xFEFF => 0x00,
+			JOYPAD_ADDRESS => joypad_register(self.data[address as usize], self.pressed_buttons),
+			0xFF00..=0xFF7F | 0xFFFF => self.data[address as usize] | register_mask(address).read,
+			_ => self.data[address as usize],
+		}
 	}
 
+	/// A write from the CPU. I/O registers only change the bits the CPU has access to and may have side effects
 	pub fn write(&mut self, address: u16, value: u8) {
-		self.data[address as usize] = value;
+		if self.flat {
+			self.data[address as usize] = value;
+			return;
+		}
+
+		if self.blocked_by_ppu(address) {
+			return;
+		}
+
+		if let Some(dma_bus) = self.dma_bus && (is_oam(address) || same_bus(address, dma_bus.address)) {
+			return;
+		}
+
+		match address {
+			DIV_ADDRESS..=TAC_ADDRESS => {
+				// Any write resets DIV, along with the counter driving it
+				let previous = self.data[address as usize];
+				self.data[address as usize] = if address == DIV_ADDRESS { 0 } else { masked_write(address, previous, value) };
+				self.timer_writes |= 1 << (address - DIV_ADDRESS);
+			}
+			DMA_ADDRESS => {
+				self.data[address as usize] = value;
+				self.dma_requested = true;
+			}
+			0xFF00..=0xFF7F | 0xFFFF => {
+				let previous = self.data[address as usize];
+				self.data[address as usize] = masked_write(address, previous, value);
+			}
+			_ => self.unblocked_write(address, value),
+		}
+	}
 
-		if address >= 0x8000 && address <= 0x97FF {
-			// println!("Writing to VRAM at {:4X} value {:2X}", address, value);
+	/// A write from the hardware itself, which can change any I/O register bit without side effects
+	pub fn unblocked_write(&mut self, address: u16, value: u8) {
+		if self.flat {
+			self.data[address as usize] = value;
+			return;
 		}
 
-		if address == 0xFF46 {
-			self.dma_requested = true;
+		match address {
+			0x0000..=0x7FFF => {
+				let rumble_active = self.mbc.rumble_active();
+				self.mbc.write_register(address, value);
+				if self.mbc.rumble_active() != rumble_active && let Some(callback) = &mut self.rumble_callback {
+					callback(!rumble_active);
+				}
+			}
+			0xA000..=0xBFFF => {
+				self.mbc.write_ram(&mut self.external_ram, address, value);
+				self.unsaved_changes |= self.has_battery;
+			}
+			0xE000..=0xFDFF => self.data[(address - 0x2000) as usize] = value,
+			0xFEA0..=0xFEFF => {}
+			_ => self.data[address as usize] = value,
 		}
 	}
 
@@ -69,22 +200,95 @@ impl Ram {
 		self.dma_requested
 	}
 
-	pub fn clear_dma_request(&mut self) {
-		self.dma_requested = false;
+	/// Whether the CPU wrote 0xFF46 since the last call
+	pub fn take_dma_request(&mut self) -> bool {
+		std::mem::take(&mut self.dma_requested)
 	}
 
-	pub fn load_rom(&mut self, rom: &[u8]) {
-		// TODO: Handle MBCs for larger ROMs and do proper length checks
-		if rom.len() > 65536 {
-			panic!(
-				"ROM size incorrect. Expected {} bytes, got {} bytes",
-				65536,
-				rom.len()
-			);
+	/// None while no transfer is running
+	pub fn set_dma_bus(&mut self, dma_bus: Option<DmaBus>) {
+		self.dma_bus = dma_bus;
+	}
+
+	/// An OAM read by the PPU, which can't see OAM while DMA is writing to it
+	pub fn ppu_read_oam(&self, address: u16) -> u8 {
+		if self.dma_bus.is_some() {
+			return 0xFF;
 		}
 
+		self.unblocked_read(address)
+	}
+
+	/// Which of DIV, TIMA, TMA and TAC the CPU wrote since the last call, as bits 0 to 3
+	pub fn take_timer_writes(&mut self) -> u8 {
+		std::mem::take(&mut self.timer_writes)
+	}
+
+	pub fn pressed_buttons(&self) -> u8 {
+		self.pressed_buttons
+	}
+
+	pub fn set_pressed_buttons(&mut self, pressed_buttons: u8) {
+		self.pressed_buttons = pressed_buttons;
+	}
+
+	pub fn load_cartridge(&mut self, cartridge: Cartridge) -> Result<(), CartridgeError> {
+		let header = cartridge.header;
+		self.mbc = create_mbc(header.cartridge_type, cartridge.rom.len())?;
+		self.rom = cartridge.rom;
+		self.has_battery = header.has_battery();
+		self.external_ram = vec![0; self.mbc.external_ram_size(header.ram_size)];
+		Ok(())
+	}
+
+	/// The ROM bank mapped into 0x4000-0x7FFF
+	pub fn rom_bank(&self) -> u16 {
+		self.mbc.rom_bank()
+	}
+
+	pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+		self.mbc.set_clock(clock);
+	}
+
+	pub fn rumble_active(&self) -> bool {
+		self.mbc.rumble_active()
+	}
+
+	pub fn set_rumble_callback(&mut self, callback: Option<Box<dyn FnMut(bool) + Send>>) {
+		self.rumble_callback = callback;
+	}
+
+	/// Only battery backed cartridges keep their save data
+	pub fn has_battery(&self) -> bool {
+		self.has_battery
+	}
+
+	/// Whether battery backed RAM changed since it was last saved
+	pub fn has_unsaved_changes(&self) -> bool {
+		self.unsaved_changes
+	}
+
+	pub fn mark_saved(&mut self) {
+		self.unsaved_changes = false;
+	}
+
+	/// External RAM followed by any other cartridge state, like the RTC
+	pub fn save_data(&self) -> Vec<u8> {
+		let mut data = self.external_ram.clone();
+		data.extend(self.mbc.save_state());
+
+		data
+	}
+
+	pub fn load_save_data(&mut self, data: &[u8]) {
+		let ram_length = self.external_ram.len().min(data.len());
+		self.external_ram[..ram_length].copy_from_slice(&data[..ram_length]);
+		self.mbc.load_state(&data[ram_length..]);
+	}
 
-		self.data[..rom.len()].copy_from_slice(rom);
+	/// The ROM bank mapped into 0x0000-0x3FFF
+	pub fn low_rom_bank(&self) -> u16 {
+		self.mbc.low_rom_bank()
 	}
 
 	pub fn interrupts_enabled(&self) -> bool {
@@ -128,13 +332,39 @@ impl TestRamOperations for Ram {
 	fn test_load(&mut self, location: u16, data: Vec<u8>) {
 		let start = location as usize;
 		let end = start + data.len();
-		self.data[start..end].copy_from_slice(&data);
+		if self.flat || start >= 0x8000 {
+			self.data[start..end].copy_from_slice(&data);
+		} else {
+			self.rom[start..end].copy_from_slice(&data);
+		}
 	}
 }
 
+/// OAM itself, along with the unusable region after it
+fn is_oam(address: u16) -> bool {
+	(0xFE00..=0xFEFF).contains(&address)
+}
+
+/// The CPU reaches VRAM over one bus and everything else outside of itself over another.
+/// I/O and HRAM are inside the CPU, so they're never on either bus
+fn same_bus(address: u16, other: u16) -> bool {
+	let bus = |address: u16| match address {
+		0x8000..=0x9FFF => Some(0),
+		0xFE00..=0xFFFF => None,
+		_ => Some(1),
+	};
+
+	bus(address).is_some() && bus(address) == bus(other)
+}
+
 #[cfg(test)]
 mod test {
 	use super::*;
+	use crate::rom::test::{set_header, test_rom};
+
+	fn load(ram: &mut Ram, rom: &[u8]) {
+		ram.load_cartridge(Cartridge::new(rom).unwrap()).unwrap();
+	}
 
 	#[test]
 	fn test_test_load() {
@@ -148,6 +378,164 @@ mod test {
 		assert_eq!(ram.read(3), 57);
 	}
 
+	#[test]
+	fn test_memory_map() {
+		let mut rom = test_rom(0x00, 0x00, 0x00);
+		rom[0x2000] = 0x12;
+		let mut ram = Ram::new();
+		load(&mut ram, &rom);
+
+		// ROM can't be written to
+		ram.write(0x2000, 0x05);
+		assert_eq!(ram.read(0x2000), 0x12);
+		assert_eq!(ram.rom_bank(), 1);
+
+		// No external RAM on this cartridge
+		ram.write(0xA000, 0x34);
+		assert_eq!(ram.read(0xA000), 0xFF);
+
+		// Echo RAM mirrors WRAM both ways
+		ram.write(0xC123, 0x56);
+		assert_eq!(ram.read(0xE123), 0x56);
+		ram.write(0xFDFF, 0x78);
+		assert_eq!(ram.read(0xDDFF), 0x78);
+
+		// Unusable region ignores writes
+		ram.write(0xFEA0, 0x9A);
+		assert_eq!(ram.read(0xFEA0), 0x00);
+
+		// Unmapped I/O reads as 0xFF
+		ram.write(0xFF4C, 0x00);
+		assert_eq!(ram.read(0xFF4C), 0xFF);
+		assert_eq!(ram.read(0xFF03), 0xFF);
+
+		let mut ram = Ram::new_flat();
+		ram.write(0x2000, 0x05);
+		ram.write(0xE000, 0x06);
+		assert_eq!(ram.read(0x2000), 0x05);
+		assert_eq!(ram.read(0xE000), 0x06);
+		assert_eq!(ram.read(0xC000), 0x00);
+	}
+
+	#[test]
+	fn test_io_registers() {
+		let mut ram = Ram::new();
+
+		// LY and the low STAT bits belong to the PPU
+		ram.unblocked_write(0xFF44, 0x90);
+		ram.write(0xFF44, 0x00);
+		assert_eq!(ram.read(0xFF44), 0x90);
+		ram.unblocked_write(0xFF41, 0b0000_0110);
+		ram.write(0xFF41, 0b0100_0001);
+		assert_eq!(ram.read(0xFF41), 0b1100_0110);
+
+		// Writing anything to DIV resets it
+		ram.unblocked_write(DIV_ADDRESS, 0x12);
+		ram.write(DIV_ADDRESS, 0x34);
+		assert_eq!(ram.read(DIV_ADDRESS), 0x00);
+		assert_eq!(ram.take_timer_writes(), 0b0001);
+		assert_eq!(ram.take_timer_writes(), 0);
+
+		// Unused bits read as 1
+		ram.write(0xFF07, 0x00);
+		assert_eq!(ram.read(0xFF07), 0b1111_1000);
+		ram.write(0xFF02, 0x81);
+		assert_eq!(ram.read(0xFF02), 0xFF);
+		assert_eq!(ram.read(0xFF26), 0b0111_0000);
+	}
+
+	#[test]
+	fn test_ppu_blocking() {
+		let mut ram = Ram::new();
+		ram.write(0x8000, 0x12);
+		ram.write(0xFE00, 0x34);
+
+		// Drawing blocks both
+		ram.unblocked_write(STAT_ADDRESS, 0b0000_0011);
+		ram.write(0x8000, 0x56);
+		ram.write(0xFE00, 0x78);
+		assert_eq!(ram.read(0x8000), 0xFF);
+		assert_eq!(ram.read(0xFE00), 0xFF);
+		assert_eq!(ram.read(0xFEA0), 0xFF);
+		assert_eq!(ram.unblocked_read(0x8000), 0x12, "The PPU itself can still read");
+
+		// OAM scan only blocks OAM
+		ram.unblocked_write(STAT_ADDRESS, 0b0000_0010);
+		assert_eq!(ram.read(0x8000), 0x12);
+		assert_eq!(ram.read(0xFE00), 0xFF);
+
+		ram.unblocked_write(STAT_ADDRESS, 0b0000_0000);
+		assert_eq!(ram.read(0xFE00), 0x34);
+		assert_eq!(ram.read(0xFEA0), 0x00);
+	}
+
+	#[test]
+	fn test_mbc1_rom() {
+		let mut rom = (0..8).flat_map(|bank| vec![bank as u8; ROM_BANK_SIZE]).collect::<Vec<u8>>();
+		set_header(&mut rom, 0x01, 0x02, 0x00);
+		let mut ram = Ram::new();
+		load(&mut ram, &rom);
+
+		assert_eq!(ram.read(0x4000), 1);
+		ram.write(0x2000, 0x05);
+		assert_eq!(ram.read(0x4000), 5);
+		assert_eq!(ram.read(0x0000), 0);
+		assert_eq!(ram.rom_bank(), 5);
+	}
+
+	#[test]
+	fn test_rumble_callback() {
+		use std::sync::{Arc, Mutex};
+
+		let rom = test_rom(0x1C, 0x00, 0x00);
+		let mut ram = Ram::new();
+		load(&mut ram, &rom);
+
+		let events = Arc::new(Mutex::new(vec![]));
+		let callback_events = events.clone();
+		ram.set_rumble_callback(Some(Box::new(move |active| callback_events.lock().unwrap().push(active))));
+
+		ram.write(0x4000, 0x08);
+		ram.write(0x4000, 0x09);
+		assert!(ram.rumble_active());
+		ram.write(0x4000, 0x00);
+		assert_eq!(*events.lock().unwrap(), vec![true, false], "Only changes are reported");
+	}
+
+	#[test]
+	fn test_external_ram() {
+		let rom = test_rom(0x08, 0x00, 0x02);
+		let mut ram = Ram::new();
+		load(&mut ram, &rom);
+
+		ram.write(0xBFFF, 0x34);
+		assert_eq!(ram.read(0xBFFF), 0x34);
+		assert!(!ram.has_unsaved_changes(), "Without a battery nothing needs saving");
+	}
+
+	#[test]
+	fn test_save_data() {
+		let rom = test_rom(0x03, 0x00, 0x02);
+		let mut ram = Ram::new();
+		load(&mut ram, &rom);
+		assert!(ram.has_battery());
+
+		ram.write(0x0000, 0x0A);
+		ram.write(0xA001, 0x34);
+		assert!(ram.has_unsaved_changes());
+		ram.mark_saved();
+		assert!(!ram.has_unsaved_changes());
+
+		let save_data = ram.save_data();
+		assert_eq!(save_data.len(), 0x2000);
+
+		let mut ram = Ram::new();
+		load(&mut ram, &rom);
+		ram.load_save_data(&save_data);
+		ram.write(0x0000, 0x0A);
+		assert_eq!(ram.read(0xA001), 0x34);
+	}
+
 	#[test]
 	fn test_interrupts() {
 		let mut ram = Ram::new();
@@ -165,12 +553,12 @@ mod test {
 
 		// Try clearing some interrupts
 		ram.clear_interrupt(Interrupt::Timer);
-		assert_eq!(ram.read(0xFF0F), 0b0000_1011);
+		assert_eq!(ram.read(0xFF0F), 0b1110_1011, "Unused bits read as 1");
 		ram.clear_interrupt(Interrupt::VBlank);
-		assert_eq!(ram.read(0xFF0F), 0b0000_1010);
+		assert_eq!(ram.read(0xFF0F), 0b1110_1010);
 
 		// Try requesting interrupts back
 		ram.request_interrupt(Interrupt::Timer);
-		assert_eq!(ram.read(0xFF0F), 0b0000_1110);
+		assert_eq!(ram.read(0xFF0F), 0b1110_1110);
 	}
 }
\ No newline at end of file
@@ -2,18 +2,22 @@ use macroquad::prelude::*;
 
 use std::sync::mpsc::{Receiver};
 use webboy::device::{ImageData};
+use webboy::lcd::{Framebuffer, SCREEN_HEIGHT, SCREEN_WIDTH};
 use webboy::tlu::TLUData;
 use webboy::palette;
 
 const SCALE_FACTOR: f32 = 2.0;
 const PADDING: i32 = 8;
+/// The tile data and tile map are drawn unscaled to the right of the LCD
+const DEBUG_VIEW_WIDTH: i32 = 32 * 8;
+const DEBUG_VIEW_HEIGHT: i32 = (8 + 32) * 8;
 
 pub fn window_conf() -> Conf {
     // Configuration for the screen
     Conf {
         window_title: "Web boy".to_owned(),
-        window_width: (32 * 8) * (SCALE_FACTOR as i32) + PADDING * 2,
-        window_height: (40 * 8) * (SCALE_FACTOR as i32) + PADDING * 3,
+        window_width: SCREEN_WIDTH as i32 * (SCALE_FACTOR as i32) + DEBUG_VIEW_WIDTH + PADDING * 3,
+        window_height: (SCREEN_HEIGHT as i32 * (SCALE_FACTOR as i32)).max(DEBUG_VIEW_HEIGHT + PADDING) + PADDING * 2,
         window_resizable: true,
         ..Default::default()
     }
@@ -30,9 +34,45 @@ pub async fn handle(rx: &Receiver<ImageData>) {
 
     if let Some(data) = latest_data {
         render_tlu_data(&data.tlu_data).await;
+        render_frame(&data.frame);
+
+        if data.rumble {
+            render_rumble_indicator();
+        }
     }
 }
 
+/// macroquad can't rumble, so a rumbling cartridge gets a shaking label instead
+fn render_rumble_indicator() {
+    let shake = if get_time().fract() < 0.5 { -SCALE_FACTOR } else { SCALE_FACTOR };
+    let x = screen_width() - 80.0 - PADDING as f32 + shake;
+    let y = PADDING as f32 + 16.0;
+    draw_rectangle(x - 4.0, y - 16.0, 80.0, 22.0, RED);
+    draw_text("RUMBLE", x, y, 22.0, WHITE);
+}
+
+fn render_frame(frame: &Framebuffer) {
+    let texture = Texture2D::from_rgba8(
+        SCREEN_WIDTH as u16,
+        SCREEN_HEIGHT as u16,
+        &frame
+            .iter()
+            .flat_map(|row| row.iter().flat_map(palette::Color::to_lcd_rgba))
+            .collect::<Vec<u8>>(),
+    );
+    texture.set_filter(FilterMode::Nearest);
+
+    draw_texture_ex(
+        &texture,
+        PADDING as f32, PADDING as f32,
+        WHITE,
+        DrawTextureParams {
+            dest_size: Some(vec2(SCREEN_WIDTH as f32 * SCALE_FACTOR, SCREEN_HEIGHT as f32 * SCALE_FACTOR)),
+            ..Default::default()
+        },
+    );
+}
+
 pub async fn render_tlu_data(tlu_data: &TLUData) {
     let width = tlu_data.tile_data[0].len() as f32;
     let height = tlu_data.tile_data.len() as f32;
@@ -68,22 +108,23 @@ pub async fn render_tlu_data(tlu_data: &TLUData) {
         Color::new(189.0 / 255.0, 192.0 / 255.0, 202.0 / 255.0, 1.0),
     );
 
+    let debug_view_x = SCREEN_WIDTH as f32 * SCALE_FACTOR + PADDING as f32 * 2.0;
     draw_texture_ex(
         &texture,
-        PADDING as f32, PADDING as f32,
+        debug_view_x, PADDING as f32,
         WHITE,
         DrawTextureParams {
-            dest_size: Some(vec2(width * SCALE_FACTOR, height * SCALE_FACTOR)),
+            dest_size: Some(vec2(width, height)),
             ..Default::default()
         },
     );
 
     draw_texture_ex(
         &tile_map_texture,
-        PADDING as f32, (64.0 * SCALE_FACTOR) + (PADDING as f32 * 2.0),
+        debug_view_x, height + (PADDING as f32 * 2.0),
         WHITE,
         DrawTextureParams {
-            dest_size: Some(vec2(tlu_data.background_data[0].len() as f32 * SCALE_FACTOR, tlu_data.background_data.len() as f32 * SCALE_FACTOR)),
+            dest_size: Some(vec2(tlu_data.background_data[0].len() as f32, tlu_data.background_data.len() as f32)),
             ..Default::default()
         },
     );
@@ -1,6 +1,317 @@
+use std::fmt;
+use crate::mbc::{RAM_BANK_SIZE, ROM_BANK_SIZE};
+
 // Boot ROM
 
 // Game ROM
 // Header with the Nintendo logo
 // Some Metadata the Gameboy doesn't actually read
-// Actual game data
\ No newline at end of file
+// Actual game data
+
+const TITLE_START: usize = 0x134;
+const MANUFACTURER_CODE_START: usize = 0x13F;
+const CGB_FLAG_ADDRESS: usize = 0x143;
+const NEW_LICENSEE_CODE_START: usize = 0x144;
+const SGB_FLAG_ADDRESS: usize = 0x146;
+const CARTRIDGE_TYPE_ADDRESS: usize = 0x147;
+const ROM_SIZE_ADDRESS: usize = 0x148;
+const RAM_SIZE_ADDRESS: usize = 0x149;
+const DESTINATION_ADDRESS: usize = 0x14A;
+const OLD_LICENSEE_CODE_ADDRESS: usize = 0x14B;
+const VERSION_ADDRESS: usize = 0x14C;
+const HEADER_CHECKSUM_ADDRESS: usize = 0x14D;
+const GLOBAL_CHECKSUM_ADDRESS: usize = 0x14E;
+/// First byte after the header
+const HEADER_END: usize = 0x150;
+
+/// The old licensee code that means the new licensee code should be used instead
+const USE_NEW_LICENSEE_CODE: u8 = 0x33;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CartridgeError {
+	/// The ROM ends before the header does
+	TooSmall(usize),
+	UnsupportedCartridgeType(u8),
+	InvalidRomSize(u8),
+	InvalidRamSize(u8),
+	/// The boot ROM refuses to start a cartridge whose header checksum doesn't match
+	HeaderChecksum { expected: u8, actual: u8 },
+}
+
+impl fmt::Display for CartridgeError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			CartridgeError::TooSmall(len) => write!(f, "ROM is only {len} bytes, too small to hold a cartridge header"),
+			CartridgeError::UnsupportedCartridgeType(cartridge_type) => {
+				write!(f, "Unsupported cartridge type {:#04X} ({})", cartridge_type, cartridge_type_name(*cartridge_type))
+			}
+			CartridgeError::InvalidRomSize(code) => write!(f, "Invalid ROM size {code:#04X} in the cartridge header"),
+			CartridgeError::InvalidRamSize(code) => write!(f, "Invalid RAM size {code:#04X} in the cartridge header"),
+			CartridgeError::HeaderChecksum { expected, actual } => {
+				write!(f, "Header checksum mismatch: the header says {expected:#04X} but it sums to {actual:#04X}")
+			}
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgbSupport {
+	/// Original Gameboy only
+	None,
+	/// Works on both, with color on a CGB
+	Enhanced,
+	Only,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+	Japan,
+	Overseas,
+}
+
+/// Everything the cartridge header at 0x100-0x14F says about the cartridge
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CartridgeHeader {
+	pub title: String,
+	/// Only present on newer cartridges, which shortened the title to make room for it
+	pub manufacturer_code: Option<String>,
+	pub cgb_support: CgbSupport,
+	pub sgb_support: bool,
+	pub old_licensee_code: u8,
+	/// Only used when the old licensee code is 0x33
+	pub new_licensee_code: Option<String>,
+	pub cartridge_type: u8,
+	/// Bytes of ROM
+	pub rom_size: usize,
+	/// Bytes of external RAM. Doesn't include RAM built into the mapper, like on MBC2
+	pub ram_size: usize,
+	pub destination: Destination,
+	pub version: u8,
+	pub header_checksum: u8,
+	pub global_checksum: u16,
+	/// Nothing checks the global checksum on hardware, so plenty of ROMs get it wrong
+	pub global_checksum_matches: bool,
+}
+
+impl CartridgeHeader {
+	pub fn parse(rom: &[u8]) -> Result<Self, CartridgeError> {
+		if rom.len() < HEADER_END {
+			return Err(CartridgeError::TooSmall(rom.len()));
+		}
+
+		let header_checksum = rom[HEADER_CHECKSUM_ADDRESS];
+		let actual = compute_header_checksum(rom);
+		if header_checksum != actual {
+			return Err(CartridgeError::HeaderChecksum { expected: header_checksum, actual });
+		}
+
+		let cgb_support = match rom[CGB_FLAG_ADDRESS] {
+			0x80 => CgbSupport::Enhanced,
+			0xC0 => CgbSupport::Only,
+			_ => CgbSupport::None,
+		};
+
+		// Cartridges with a CGB flag use the end of the title for the flag and the manufacturer code
+		let (title, manufacturer_code) = if cgb_support == CgbSupport::None {
+			(header_string(&rom[TITLE_START..CGB_FLAG_ADDRESS + 1]), None)
+		} else {
+			let manufacturer_code = header_string(&rom[MANUFACTURER_CODE_START..CGB_FLAG_ADDRESS]);
+			let manufacturer_code = Some(manufacturer_code).filter(|code| code.len() == 4);
+			(header_string(&rom[TITLE_START..MANUFACTURER_CODE_START]), manufacturer_code)
+		};
+
+		let old_licensee_code = rom[OLD_LICENSEE_CODE_ADDRESS];
+		let new_licensee_code = (old_licensee_code == USE_NEW_LICENSEE_CODE)
+			.then(|| header_string(&rom[NEW_LICENSEE_CODE_START..SGB_FLAG_ADDRESS]));
+
+		let global_checksum = u16::from_be_bytes([rom[GLOBAL_CHECKSUM_ADDRESS], rom[GLOBAL_CHECKSUM_ADDRESS + 1]]);
+
+		Ok(Self {
+			title,
+			manufacturer_code,
+			cgb_support,
+			sgb_support: rom[SGB_FLAG_ADDRESS] == 0x03,
+			old_licensee_code,
+			new_licensee_code,
+			cartridge_type: rom[CARTRIDGE_TYPE_ADDRESS],
+			rom_size: rom_size(rom[ROM_SIZE_ADDRESS])?,
+			ram_size: ram_size(rom[RAM_SIZE_ADDRESS])?,
+			destination: if rom[DESTINATION_ADDRESS] == 0x00 { Destination::Japan } else { Destination::Overseas },
+			version: rom[VERSION_ADDRESS],
+			header_checksum,
+			global_checksum,
+			global_checksum_matches: global_checksum == compute_global_checksum(rom),
+		})
+	}
+
+	/// Whether a battery keeps the cartridge RAM (and RTC) alive
+	pub fn has_battery(&self) -> bool {
+		matches!(self.cartridge_type, 0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0xFF)
+	}
+}
+
+/// A ROM along with its parsed header
+pub struct Cartridge {
+	pub header: CartridgeHeader,
+	/// Padded out to the size in the header
+	pub rom: Vec<u8>,
+}
+
+impl Cartridge {
+	pub fn new(rom: &[u8]) -> Result<Self, CartridgeError> {
+		let header = CartridgeHeader::parse(rom)?;
+		let mut rom = rom.to_vec();
+		rom.resize(rom.len().max(header.rom_size), 0xFF);
+
+		Ok(Self { header, rom })
+	}
+}
+
+/// The checksum the boot ROM verifies, over 0x134-0x14C
+pub fn compute_header_checksum(rom: &[u8]) -> u8 {
+	rom[TITLE_START..HEADER_CHECKSUM_ADDRESS]
+		.iter()
+		.fold(0u8, |checksum, byte| checksum.wrapping_sub(*byte).wrapping_sub(1))
+}
+
+/// Sum of every byte in the ROM except the global checksum itself
+fn compute_global_checksum(rom: &[u8]) -> u16 {
+	rom.iter()
+		.enumerate()
+		.filter(|(i, _)| *i != GLOBAL_CHECKSUM_ADDRESS && *i != GLOBAL_CHECKSUM_ADDRESS + 1)
+		.fold(0u16, |checksum, (_, byte)| checksum.wrapping_add(*byte as u16))
+}
+
+/// Header strings are ASCII, padded with zeroes
+fn header_string(bytes: &[u8]) -> String {
+	bytes.iter()
+		.take_while(|byte| **byte != 0)
+		.map(|byte| if byte.is_ascii_graphic() || *byte == b' ' { *byte as char } else { '?' })
+		.collect::<String>()
+		.trim_end()
+		.to_string()
+}
+
+fn rom_size(code: u8) -> Result<usize, CartridgeError> {
+	match code {
+		0x00..=0x08 => Ok((2 * ROM_BANK_SIZE) << code),
+		_ => Err(CartridgeError::InvalidRomSize(code)),
+	}
+}
+
+fn ram_size(code: u8) -> Result<usize, CartridgeError> {
+	match code {
+		// 0x01 was never used by a released cartridge
+		0x00 | 0x01 => Ok(0),
+		0x02 => Ok(RAM_BANK_SIZE),
+		0x03 => Ok(4 * RAM_BANK_SIZE),
+		0x04 => Ok(16 * RAM_BANK_SIZE),
+		0x05 => Ok(8 * RAM_BANK_SIZE),
+		_ => Err(CartridgeError::InvalidRamSize(code)),
+	}
+}
+
+pub fn cartridge_type_name(cartridge_type: u8) -> &'static str {
+	match cartridge_type {
+		0x00 => "ROM ONLY",
+		0x01 => "MBC1",
+		0x02 => "MBC1+RAM",
+		0x03 => "MBC1+RAM+BATTERY",
+		0x05 => "MBC2",
+		0x06 => "MBC2+BATTERY",
+		0x08 => "ROM+RAM",
+		0x09 => "ROM+RAM+BATTERY",
+		0x0B => "MMM01",
+		0x0C => "MMM01+RAM",
+		0x0D => "MMM01+RAM+BATTERY",
+		0x0F => "MBC3+TIMER+BATTERY",
+		0x10 => "MBC3+TIMER+RAM+BATTERY",
+		0x11 => "MBC3",
+		0x12 => "MBC3+RAM",
+		0x13 => "MBC3+RAM+BATTERY",
+		0x19 => "MBC5",
+		0x1A => "MBC5+RAM",
+		0x1B => "MBC5+RAM+BATTERY",
+		0x1C => "MBC5+RUMBLE",
+		0x1D => "MBC5+RUMBLE+RAM",
+		0x1E => "MBC5+RUMBLE+RAM+BATTERY",
+		0x20 => "MBC6",
+		0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
+		0xFC => "POCKET CAMERA",
+		0xFD => "BANDAI TAMA5",
+		0xFE => "HuC3",
+		0xFF => "HuC1+RAM+BATTERY",
+		_ => "unknown",
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+	use super::*;
+
+	/// A blank ROM with a valid header for the given cartridge type and sizes
+	pub(crate) fn test_rom(cartridge_type: u8, rom_size_code: u8, ram_size_code: u8) -> Vec<u8> {
+		let mut rom = vec![0; rom_size(rom_size_code).unwrap()];
+		set_header(&mut rom, cartridge_type, rom_size_code, ram_size_code);
+		rom
+	}
+
+	/// Fills in the header of an existing ROM, fixing up the checksum
+	pub(crate) fn set_header(rom: &mut [u8], cartridge_type: u8, rom_size_code: u8, ram_size_code: u8) {
+		rom[CARTRIDGE_TYPE_ADDRESS] = cartridge_type;
+		rom[ROM_SIZE_ADDRESS] = rom_size_code;
+		rom[RAM_SIZE_ADDRESS] = ram_size_code;
+		rom[HEADER_CHECKSUM_ADDRESS] = compute_header_checksum(rom);
+	}
+
+	#[test]
+	fn test_parse_header() {
+		let mut rom = test_rom(0x13, 0x05, 0x03);
+		rom[TITLE_START..TITLE_START + 6].copy_from_slice(b"POKEMO");
+		rom[MANUFACTURER_CODE_START..CGB_FLAG_ADDRESS].copy_from_slice(b"AAXE");
+		rom[CGB_FLAG_ADDRESS] = 0x80;
+		rom[NEW_LICENSEE_CODE_START..SGB_FLAG_ADDRESS].copy_from_slice(b"01");
+		rom[SGB_FLAG_ADDRESS] = 0x03;
+		rom[DESTINATION_ADDRESS] = 0x01;
+		rom[OLD_LICENSEE_CODE_ADDRESS] = USE_NEW_LICENSEE_CODE;
+		rom[VERSION_ADDRESS] = 0x01;
+		rom[HEADER_CHECKSUM_ADDRESS] = compute_header_checksum(&rom);
+		let global_checksum = compute_global_checksum(&rom);
+		rom[GLOBAL_CHECKSUM_ADDRESS..HEADER_END].copy_from_slice(&global_checksum.to_be_bytes());
+
+		let header = CartridgeHeader::parse(&rom).unwrap();
+		assert_eq!(header.title, "POKEMO");
+		assert_eq!(header.manufacturer_code.as_deref(), Some("AAXE"));
+		assert_eq!(header.cgb_support, CgbSupport::Enhanced);
+		assert!(header.sgb_support);
+		assert_eq!(header.new_licensee_code.as_deref(), Some("01"));
+		assert_eq!(header.rom_size, 1024 * 1024);
+		assert_eq!(header.ram_size, 32 * 1024);
+		assert_eq!(header.destination, Destination::Overseas);
+		assert_eq!(header.version, 0x01);
+		assert!(header.has_battery());
+		assert!(header.global_checksum_matches);
+
+		// Older cartridges use all 16 bytes for the title
+		let mut rom = test_rom(0x00, 0x00, 0x00);
+		rom[TITLE_START..CGB_FLAG_ADDRESS + 1].copy_from_slice(b"SIXTEEN CHAR NAM");
+		rom[HEADER_CHECKSUM_ADDRESS] = compute_header_checksum(&rom);
+		let header = CartridgeHeader::parse(&rom).unwrap();
+		assert_eq!(header.title, "SIXTEEN CHAR NAM");
+		assert_eq!(header.manufacturer_code, None);
+		assert_eq!(header.new_licensee_code, None);
+		assert!(!header.global_checksum_matches);
+	}
+
+	#[test]
+	fn test_invalid_header() {
+		assert_eq!(CartridgeHeader::parse(&[0; 0x100]), Err(CartridgeError::TooSmall(0x100)));
+
+		let mut rom = test_rom(0x00, 0x00, 0x00);
+		rom[VERSION_ADDRESS] = 0x01;
+		assert!(matches!(CartridgeHeader::parse(&rom), Err(CartridgeError::HeaderChecksum { .. })));
+
+		set_header(&mut rom, 0x00, 0x00, 0x09);
+		assert_eq!(CartridgeHeader::parse(&rom), Err(CartridgeError::InvalidRamSize(0x09)));
+	}
+}
@@ -0,0 +1,52 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// The save file sits next to the ROM, e.g. `game.gb` saves to `game.sav`
+pub fn save_path(rom_path: impl AsRef<Path>) -> PathBuf {
+	rom_path.as_ref().with_extension("sav")
+}
+
+/// Returns None when there's no save yet
+pub fn read_save(path: impl AsRef<Path>) -> io::Result<Option<Vec<u8>>> {
+	match fs::read(path) {
+		Ok(data) => Ok(Some(data)),
+		Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+		Err(e) => Err(e),
+	}
+}
+
+/// Writes to a temporary file first, then renames it over the save. A crash either leaves the old save or the
+/// new one, never a partially written file
+pub fn write_save(path: impl AsRef<Path>, data: &[u8]) -> io::Result<()> {
+	let path = path.as_ref();
+	let mut temp_path = path.as_os_str().to_owned();
+	temp_path.push(".tmp");
+	let temp_path = PathBuf::from(temp_path);
+
+	let mut file = File::create(&temp_path)?;
+	file.write_all(data)?;
+	file.sync_all()?;
+	fs::rename(&temp_path, path)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_save_roundtrip() {
+		let dir = std::env::temp_dir().join(format!("webboy-save-test-{}", std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let path = save_path(dir.join("game.gb"));
+		assert_eq!(path, dir.join("game.sav"));
+
+		assert_eq!(read_save(&path).unwrap(), None);
+		write_save(&path, &[1, 2, 3]).unwrap();
+		write_save(&path, &[4, 5]).unwrap();
+		assert_eq!(read_save(&path).unwrap(), Some(vec![4, 5]));
+		assert!(!dir.join("game.sav.tmp").exists(), "The temporary file should be renamed away");
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
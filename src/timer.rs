@@ -2,77 +2,125 @@ use crate::cpu::instruction::MCycles;
 use crate::ram::{Interrupt, Ram};
 
 const M_CYCLES_TO_CLOCK_CYCLES: u16 = 4;
-const M_CYCLES_TO_DIV_INCREMENT: u16 = 64;
-
 
 const DIV_ADDRESS: u16 = 0xFF04;
 const TIMA_ADDRESS: u16 = 0xFF05;
 const TMA_ADDRESS: u16 = 0xFF06;
 const TAC_ADDRESS: u16 = 0xFF07;
 
+/// Bits of `Ram::take_timer_writes`
+const DIV_WRITTEN: u8 = 0b0001;
+const TIMA_WRITTEN: u8 = 0b0010;
+const TMA_WRITTEN: u8 = 0b0100;
+
+/// What happens after TIMA overflows
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Reload {
+	None,
+	/// TIMA overflowed and reads 0x00 for an M-cycle. Writing TIMA now cancels the reload and the interrupt
+	Pending,
+	/// TMA was just copied into TIMA. TIMA ignores writes for this M-cycle while TMA writes go to both
+	Reloading,
+}
+
+/// DIV is the upper byte of a 16-bit counter that goes up every clock cycle.
+/// TIMA goes up whenever the counter bit selected by TAC goes from 1 to 0 while the timer is enabled,
+/// so resetting DIV or changing TAC can increment TIMA too
 pub struct Timer {
 	pub cycles: u128,
-	cycles_since_div: u16,
-	cycles_since_tima: u16,
+	system_counter: u16,
+	/// The selected counter bit ANDed with the enable bit, as of the last M-cycle
+	timer_signal: bool,
+	reload: Reload,
 }
 
-// TODO: Handle writing to DIV register (resets to 0)
-// TODO: Handle edge cases with TIMA increments and writes
 impl Timer {
 	pub fn new() -> Self {
 		Timer {
 			cycles: 0,
-			cycles_since_div: 0,
-			cycles_since_tima: 0,
+			system_counter: 0,
+			timer_signal: false,
+			reload: Reload::None,
 		}
 	}
 
-	pub fn enabled(ram: &mut Ram) -> bool {
+	/// Sets the counter without any of the side effects of a DIV write, like the state the boot ROM leaves it in
+	pub fn set_system_counter(&mut self, ram: &mut Ram, system_counter: u16) {
+		self.system_counter = system_counter;
+		ram.unblocked_write(DIV_ADDRESS, (system_counter >> 8) as u8);
+	}
+
+	/// Resets DIV along with the internal counter that drives it
+	pub fn reset_div(&mut self, ram: &mut Ram) {
+		self.set_system_counter(ram, 0);
+	}
+
+	pub fn enabled(ram: &Ram) -> bool {
 		(ram.unblocked_read(TAC_ADDRESS) & 0b0000_0100) != 0
 	}
 
 	pub fn increment_cycle(&mut self, ram: &mut Ram, cycle_count: MCycles) {
-		if cycle_count > 6 {
-			panic!("timer: Invalid cycle count increase of {}", cycle_count);
+		for _ in 0..cycle_count {
+			self.tick(ram);
 		}
+	}
 
-		self.cycles = self.cycles.wrapping_add(cycle_count as u128);
-		self.cycles_since_div += cycle_count as u16;
-		let tima_enabled = Timer::enabled(ram);
-		self.cycles_since_tima += if tima_enabled { cycle_count as u16 } else { 0 };
+	fn tick(&mut self, ram: &mut Ram) {
+		self.cycles = self.cycles.wrapping_add(1);
 
-		// DIV is always incremented at the cycle interval
-		if self.cycles_since_div >= M_CYCLES_TO_DIV_INCREMENT {
-			ram.write(DIV_ADDRESS, ram.unblocked_read(DIV_ADDRESS).wrapping_add(1));
-			self.cycles_since_div -= M_CYCLES_TO_DIV_INCREMENT;
+		// Registers the CPU wrote during the last M-cycle
+		let writes = ram.take_timer_writes();
+		if writes & DIV_WRITTEN != 0 {
+			self.system_counter = 0;
 		}
 
-		// TIMA is incremented based on the TMA register
-		let cycles_to_tma = Timer::cycles_to_tma(ram);
-		if self.cycles_since_tima >= cycles_to_tma && Timer::enabled(ram) {
-			self.cycles_since_tima -= cycles_to_tma;
-
-			let (res, overflow) = ram.unblocked_read(TIMA_ADDRESS).overflowing_add(1u8);
-			ram.write(TIMA_ADDRESS, res);
-
-			// When TIMA overflows, we reset and send an interrupt
-			if overflow {
-				ram.write(TIMA_ADDRESS, ram.unblocked_read(TMA_ADDRESS));
+		self.reload = match self.reload {
+			Reload::Pending if writes & TIMA_WRITTEN != 0 => Reload::None,
+			Reload::Pending => {
+				ram.unblocked_write(TIMA_ADDRESS, ram.unblocked_read(TMA_ADDRESS));
 				ram.request_interrupt(Interrupt::Timer);
+				Reload::Reloading
 			}
+			Reload::Reloading => {
+				if writes & (TIMA_WRITTEN | TMA_WRITTEN) != 0 {
+					ram.unblocked_write(TIMA_ADDRESS, ram.unblocked_read(TMA_ADDRESS));
+				}
+				Reload::None
+			}
+			Reload::None => Reload::None,
+		};
+
+		let previous_div = (self.system_counter >> 8) as u8;
+		self.system_counter = self.system_counter.wrapping_add(M_CYCLES_TO_CLOCK_CYCLES);
+		let div = (self.system_counter >> 8) as u8;
+		if writes & DIV_WRITTEN != 0 || div != previous_div {
+			ram.unblocked_write(DIV_ADDRESS, div);
+		}
+
+		let timer_signal = Timer::enabled(ram) && self.system_counter & Timer::selected_bit(ram) != 0;
+		if self.timer_signal && !timer_signal {
+			self.increment_tima(ram);
 		}
+		self.timer_signal = timer_signal;
 	}
 
-	pub fn cycles_to_tma(ram: &Ram) -> u16 {
-		let tac = ram.unblocked_read(TAC_ADDRESS);
-		let control_bits = tac & 0b0000_0011;
+	fn increment_tima(&mut self, ram: &mut Ram) {
+		let (tima, overflow) = ram.unblocked_read(TIMA_ADDRESS).overflowing_add(1);
+		ram.unblocked_write(TIMA_ADDRESS, tima);
 
-		match control_bits {
-			0b00 => 256,
-			0b01 => 4,
-			0b10 => 16,
-			0b11 => 64,
-			bit => panic!("timer: Invalid control bit of {bit}"),
+		// TMA is only loaded on the next M-cycle, until then TIMA reads 0x00
+		if overflow {
+			self.reload = Reload::Pending;
+		}
+	}
+
+	/// The system counter bit TIMA follows
+	fn selected_bit(ram: &Ram) -> u16 {
+		match ram.unblocked_read(TAC_ADDRESS) & 0b0000_0011 {
+			0b00 => 1 << 9,
+			0b01 => 1 << 3,
+			0b10 => 1 << 5,
+			_ => 1 << 7,
 		}
 	}
 }
@@ -83,58 +131,105 @@ mod test {
 
 	#[test]
 	fn test_increment() {
-		let mut timer = Timer {
-			cycles: 0,
-			cycles_since_div: 61,
-			cycles_since_tima: 2,
-		};
-
+		let mut timer = Timer::new();
 		let mut ram = Ram::new();
 		ram.write(TAC_ADDRESS, 0b0000_0101);
 		ram.write(TMA_ADDRESS, 70);
 
-		timer.increment_cycle(&mut ram, 1);
+		// Every 16 clock cycles
+		timer.increment_cycle(&mut ram, 3);
 		assert_eq!(ram.unblocked_read(TIMA_ADDRESS), 0, "Value should not have been incremented");
-		assert_eq!(ram.unblocked_read(DIV_ADDRESS), 0, "Value should not have been incremented");
-
-		// TIMA inc
 		timer.increment_cycle(&mut ram, 1);
 		assert_eq!(ram.unblocked_read(TIMA_ADDRESS), 1, "Value should have been incremented");
-		assert_eq!(timer.cycles_since_tima, 0, "TIMA Cycles should have been reset");
-		assert_eq!(ram.unblocked_read(DIV_ADDRESS), 0, "Value should not have been incremented");
 
-		// DIV inc
-		timer.increment_cycle(&mut ram, 1);
-		assert_eq!(ram.unblocked_read(TIMA_ADDRESS), 1, "Value should not have been incremented");
-		assert_eq!(ram.unblocked_read(DIV_ADDRESS), 1, "Value should have been incremented");
-		assert_eq!(timer.cycles_since_div, 0, "TIMA Cycles should have been reset");
+		// DIV is the upper byte of the counter
+		timer.increment_cycle(&mut ram, 60);
+		assert_eq!(ram.unblocked_read(DIV_ADDRESS), 1);
+		assert_eq!(ram.unblocked_read(TIMA_ADDRESS), 16);
 
-		// TIMA overflow
+		// TIMA overflow. TIMA reads 0 for an M-cycle before TMA is loaded
 		ram.write(TIMA_ADDRESS, 0xFF);
-		timer.cycles_since_tima = 3;
+		timer.increment_cycle(&mut ram, 4);
+		assert_eq!(ram.unblocked_read(TIMA_ADDRESS), 0);
+		assert_eq!(ram.unblocked_read(0xFF0F), 0b1110_0000, "The interrupt is delayed along with the reload");
 		timer.increment_cycle(&mut ram, 1);
-
 		assert_eq!(ram.unblocked_read(TIMA_ADDRESS), 70, "Value should have been set to TMA");
-		assert_eq!(timer.cycles_since_tima, 0, "TIMA Cycles should have been reset");
-		assert_eq!(timer.cycles_since_div, 1, "TIMA Cycles should have been reset");
-		assert_eq!(ram.unblocked_read(0xFF0F), 0b0000_0100, "The timer interrupt request should be set");
+		assert_eq!(ram.unblocked_read(0xFF0F), 0b1110_0100, "The timer interrupt request should be set");
 	}
 
 	#[test]
 	fn test_disabled() {
-		let mut timer = Timer {
-			cycles: 0,
-			cycles_since_div: 0,
-			cycles_since_tima: 0,
-		};
+		let mut timer = Timer::new();
+		let mut ram = Ram::new();
+		ram.write(TAC_ADDRESS, 0b0000_0001);
 
+		timer.increment_cycle(&mut ram, 64);
+		assert_eq!(timer.cycles, 64);
+		assert_eq!(ram.unblocked_read(DIV_ADDRESS), 1, "DIV always runs");
+		assert_eq!(ram.unblocked_read(TIMA_ADDRESS), 0, "Value should not have been incremented");
+	}
+
+	#[test]
+	fn test_falling_edge_glitches() {
+		let mut timer = Timer::new();
 		let mut ram = Ram::new();
+		ram.write(TAC_ADDRESS, 0b0000_0101);
+
+		// Resetting DIV while the selected bit is set increments TIMA early
+		timer.increment_cycle(&mut ram, 2);
+		ram.write(DIV_ADDRESS, 0x00);
+		timer.increment_cycle(&mut ram, 1);
+		assert_eq!(ram.unblocked_read(TIMA_ADDRESS), 1);
+
+		// So does disabling the timer while the selected bit is set
+		timer.increment_cycle(&mut ram, 1);
 		ram.write(TAC_ADDRESS, 0b0000_0001);
-		ram.write(TMA_ADDRESS, 70);
+		timer.increment_cycle(&mut ram, 1);
+		assert_eq!(ram.unblocked_read(TIMA_ADDRESS), 2);
 
+		// But not while it's clear
+		ram.write(TAC_ADDRESS, 0b0000_0101);
 		timer.increment_cycle(&mut ram, 1);
-		assert_eq!(timer.cycles, 1, "Value should not have been incremented");
-		assert_eq!(timer.cycles_since_div, 1, "Value should not have been incremented");
-		assert_eq!(timer.cycles_since_tima, 0, "Value should not have been incremented");
+		ram.write(TAC_ADDRESS, 0b0000_0001);
+		timer.increment_cycle(&mut ram, 1);
+		assert_eq!(ram.unblocked_read(TIMA_ADDRESS), 2);
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn test_writes_around_reload() {
+		let overflowed_timer = |ram: &mut Ram| {
+			let mut timer = Timer::new();
+			ram.write(TAC_ADDRESS, 0b0000_0101);
+			ram.write(TIMA_ADDRESS, 0xFF);
+			ram.write(TMA_ADDRESS, 70);
+			timer.increment_cycle(ram, 4);
+			assert_eq!(ram.unblocked_read(TIMA_ADDRESS), 0);
+			timer
+		};
+
+		// Writing TIMA before the reload cancels it along with the interrupt
+		let mut ram = Ram::new();
+		let mut timer = overflowed_timer(&mut ram);
+		ram.write(TIMA_ADDRESS, 0x12);
+		timer.increment_cycle(&mut ram, 1);
+		assert_eq!(ram.unblocked_read(TIMA_ADDRESS), 0x12);
+		assert_eq!(ram.pending_interrupt(), None);
+		assert_eq!(ram.unblocked_read(0xFF0F), 0b1110_0000);
+
+		// Writing TIMA during the reload is ignored
+		let mut ram = Ram::new();
+		let mut timer = overflowed_timer(&mut ram);
+		timer.increment_cycle(&mut ram, 1);
+		ram.write(TIMA_ADDRESS, 0x12);
+		timer.increment_cycle(&mut ram, 1);
+		assert_eq!(ram.unblocked_read(TIMA_ADDRESS), 70);
+
+		// Writing TMA during the reload goes to TIMA as well
+		let mut ram = Ram::new();
+		let mut timer = overflowed_timer(&mut ram);
+		timer.increment_cycle(&mut ram, 1);
+		ram.write(TMA_ADDRESS, 0x34);
+		timer.increment_cycle(&mut ram, 1);
+		assert_eq!(ram.unblocked_read(TIMA_ADDRESS), 0x34);
+	}
+}
@@ -14,12 +14,7 @@ pub struct TLUData {
 
 impl TLU {
 	fn get_tile_by_index(ram: &Ram, tile_index: u8) -> [[Color; 8]; 8] {
-		let block_start: u16 = if ram.bg_and_window_tile_data_control() { 0x8000 } else { 0x9000 };
-		let offset = if ram.bg_and_window_tile_data_control() { tile_index as i16 * 16 } else { (tile_index as i8 as i16) * 16 };
-
-		let tile_start_address = block_start.wrapping_add_signed(offset);
-
-		TLU::get_tile_at_location(ram, tile_start_address)
+		TLU::get_tile_at_location(ram, ram.bg_and_window_tile_address(tile_index))
 	}
 
 	fn get_tile_at_location(ram: &Ram, tile_start_address: u16) -> [[Color; 8]; 8] {
@@ -1,10 +1,17 @@
 use serde_json::{Result, Value};
 use std::fs;
 use std::path::PathBuf;
-use webboy::cpu::CPU;
+use webboy::cpu::{BusActivity, CPU};
 use webboy::cpu::register::Registers;
 use webboy::ram::{Ram};
 
+/// Results for every test within a single JSON file, which covers a single opcode
+struct OpcodeReport {
+	opcode: String,
+	test_count: usize,
+	failures: Vec<String>,
+}
+
 fn read_file(file_name: &str) -> Result<Value> {
 	let data: String = fs::read_to_string(file_name).unwrap();
 	serde_json::from_str(&data)
@@ -12,46 +19,65 @@ fn read_file(file_name: &str) -> Result<Value> {
 
 #[test]
 fn test_all_jsons() {
-	let paths = fs::read_dir("./tests/cpu_tests").unwrap();
-	let mut test_ran: bool = false;
-
-	for result in paths {
-		test_ran = true;
-		let path = result.unwrap().path();
-		println!("Running test file: {:?}", path);
-		test_json(path);
+	let mut paths = fs::read_dir("./tests/cpu_tests").into_iter().flatten()
+		.map(|result| result.unwrap().path())
+		.collect::<Vec<PathBuf>>();
+	paths.sort();
+
+	if paths.is_empty() {
+		panic!("No CPU tests found. You'll need to manually download the JSON specs in the v1 folder of https://github.com/SingleStepTests/sm83 and place them in the tests/cpu_tests folder. (I'm too lazy to write a script for this- sorry!)")
 	}
 
-	if !test_ran {
-		panic!("No CPU tests found. You'll need to manually download the JSON specs from https://github.com/adtennant/GameboyCPUTests/tree/master and place them in the cpu_test folder. (I'm too lazy to write a script for this- sorry!)")
+	let reports = paths.into_iter().map(test_json).collect::<Vec<OpcodeReport>>();
+	let failing_reports = reports.iter().filter(|report| !report.failures.is_empty()).collect::<Vec<&OpcodeReport>>();
+
+	for report in &failing_reports {
+		println!(
+			"Opcode {}: {}/{} tests failed. First failure: {}",
+			report.opcode,
+			report.failures.len(),
+			report.test_count,
+			report.failures[0]
+		);
+	}
+
+	if !failing_reports.is_empty() {
+		panic!("{} of {} opcodes failed", failing_reports.len(), reports.len());
 	}
 }
 
-fn test_json(path: PathBuf) {
+fn test_json(path: PathBuf) -> OpcodeReport {
 	let test_json = read_file(path.to_str().unwrap()).unwrap();
 	let tests_array = test_json.as_array().unwrap();
 
-	for (i, test) in tests_array.iter().enumerate() {
-		println!("Running test {}, with name {:?}", i, test["name"].as_str().unwrap());
-		run_test(test);
+	let failures = tests_array.iter()
+		.filter_map(|test| {
+			run_test(test).err().map(|e| format!("{:?}: {}", test["name"].as_str().unwrap(), e))
+		})
+		.collect();
+
+	OpcodeReport {
+		opcode: path.file_stem().unwrap().to_string_lossy().to_string(),
+		test_count: tests_array.len(),
+		failures,
 	}
 }
 
-fn run_test(test: &Value) {
+fn run_test(test: &Value) -> std::result::Result<(), String> {
 	// Initialize
 	let initial_values = &test["initial"];
 	let mut cpu = CPU::new();
+	cpu.ram = Ram::new_flat();
 	set_ram(&mut cpu.ram, initial_values);
 
 	set_registers(&mut cpu.registers, initial_values);
 
 	// Run operation
-	println!("Running instruction 0o{:o}", cpu.ram.read(cpu.registers.pc));
+	cpu.record_bus_activity(true);
 	cpu.execute(false);
-	cpu.print_cpu();
 
 	// Validate
-	assert_expected(&cpu, &test);
+	assert_expected(&mut cpu, test)
 }
 
 fn set_registers(registers: &mut Registers, initial_values: &Value) {
@@ -74,34 +100,76 @@ fn set_ram(ram: &mut Ram, initial_values: &Value) {
 	}
 }
 
-fn assert_expected(cpu: &CPU, test: &Value) {
+fn check<T: PartialEq + std::fmt::Debug>(actual: T, expected: T, name: &str) -> std::result::Result<(), String> {
+	if actual != expected {
+		return Err(format!("{name} mismatch: expected {expected:?}, got {actual:?}"));
+	}
+
+	Ok(())
+}
+
+fn assert_expected(cpu: &mut CPU, test: &Value) -> std::result::Result<(), String> {
 	let final_values = &test["final"];
 
 	// Test registers
-	assert_eq!(cpu.registers.a, final_values["a"].as_u64().unwrap() as u8, "Register A mismatch");
-	assert_eq!(cpu.registers.b, final_values["b"].as_u64().unwrap() as u8, "Register B mismatch");
-	assert_eq!(cpu.registers.c, final_values["c"].as_u64().unwrap() as u8, "Register C mismatch");
-	assert_eq!(cpu.registers.d, final_values["d"].as_u64().unwrap() as u8, "Register D mismatch");
-	assert_eq!(cpu.registers.e, final_values["e"].as_u64().unwrap() as u8, "Register E mismatch");
-	assert_eq!(cpu.registers.f, final_values["f"].as_u64().unwrap() as u8, "Register F mismatch");
-	assert_eq!(cpu.registers.h, final_values["h"].as_u64().unwrap() as u8, "Register H mismatch");
-	assert_eq!(cpu.registers.l, final_values["l"].as_u64().unwrap() as u8, "Register L mismatch");
-	assert_eq!(cpu.registers.pc, (final_values["pc"].as_u64().unwrap() - 1) as u16, "PC mismatch");
-	assert_eq!(cpu.registers.get_sp(), final_values["sp"].as_u64().unwrap() as u16, "SP mismatch");
+	check(cpu.registers.a, final_values["a"].as_u64().unwrap() as u8, "Register A")?;
+	check(cpu.registers.b, final_values["b"].as_u64().unwrap() as u8, "Register B")?;
+	check(cpu.registers.c, final_values["c"].as_u64().unwrap() as u8, "Register C")?;
+	check(cpu.registers.d, final_values["d"].as_u64().unwrap() as u8, "Register D")?;
+	check(cpu.registers.e, final_values["e"].as_u64().unwrap() as u8, "Register E")?;
+	check(cpu.registers.f, final_values["f"].as_u64().unwrap() as u8, "Register F")?;
+	check(cpu.registers.h, final_values["h"].as_u64().unwrap() as u8, "Register H")?;
+	check(cpu.registers.l, final_values["l"].as_u64().unwrap() as u8, "Register L")?;
+	check(cpu.registers.pc, (final_values["pc"].as_u64().unwrap() - 1) as u16, "PC")?;
+	check(cpu.registers.get_sp(), final_values["sp"].as_u64().unwrap() as u16, "SP")?;
 
 	// Test ram
 	let final_ram = &final_values["ram"];
 	for ram_array in final_ram.as_array().unwrap() {
 		let array_values = ram_array.as_array().unwrap();
-		assert_eq!(
-			cpu.ram.read(array_values[0].as_u64().unwrap() as u16),
-			array_values[1].as_u64().unwrap() as u8,
-			"RAM value mismatch at address 0x{:04X}", array_values[0].as_u64().unwrap() as u16
-		);
+		let address = array_values[0].as_u64().unwrap() as u16;
+		check(cpu.ram.read(address), array_values[1].as_u64().unwrap() as u8, &format!("RAM value at 0x{address:04X}"))?;
 	}
 
 	// Test cycles
-	// TODO: Add cycle tests
-	let expected_cycle_count = test["cycles"].as_array().unwrap().len();
-	assert_eq!(cpu.timer.cycles as usize, expected_cycle_count, "Cycle count mismatch");
-}
\ No newline at end of file
+	let expected_cycles = test["cycles"].as_array().unwrap().iter().map(parse_cycle).collect::<Vec<BusActivity>>();
+	check(cpu.timer.cycles as usize, expected_cycles.len(), "Cycle count")?;
+	let initial_pc = (test["initial"]["pc"].as_u64().unwrap() - 1) as u16;
+	check_bus_activity(&cpu.take_bus_activity(), &expected_cycles, initial_pc)
+}
+
+/// Cycles are either null for internal cycles or [address, value, kind]
+fn parse_cycle(cycle: &Value) -> BusActivity {
+	let Some(values) = cycle.as_array() else {
+		return BusActivity::Internal;
+	};
+
+	let address = values[0].as_u64().unwrap_or(0) as u16;
+	let value = values[1].as_u64().unwrap_or(0) as u8;
+	match values[2].as_str().unwrap() {
+		"read" | "r-m" => BusActivity::Read { address, value },
+		"write" | "-wm" => BusActivity::Write { address, value },
+		_ => BusActivity::Internal,
+	}
+}
+
+/// The vectors assume the opcode was already fetched and end with the fetch of the next opcode instead.
+/// Our CPU fetches the opcode from the initial PC as its first cycle, so everything after it lines up with the
+/// expected cycles minus the final fetch
+fn check_bus_activity(actual: &[BusActivity], expected: &[BusActivity], initial_pc: u16) -> std::result::Result<(), String> {
+	check(actual.len(), expected.len(), "Bus cycle count")?;
+	let Some((fetch, actual)) = actual.split_first() else {
+		return Ok(());
+	};
+
+	let BusActivity::Read { address, .. } = fetch else {
+		return Err(format!("Bus cycle 0 mismatch: expected the opcode fetch, got {fetch:?}"));
+	};
+	check(*address, initial_pc, "Bus cycle 0 address")?;
+
+	for (i, (actual, expected)) in actual.iter().zip(expected).enumerate() {
+		check(actual, expected, &format!("Bus cycle {}", i + 1))?;
+	}
+
+	Ok(())
+}
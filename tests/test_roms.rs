@@ -0,0 +1,57 @@
+use std::fs;
+use std::sync::mpsc::channel;
+use webboy::device::Device;
+use webboy::lcd::Framebuffer;
+use webboy::palette::Color;
+
+/// Far more ticks than a frame takes, so a ROM that turns the LCD off fails instead of hanging
+const MAX_TICKS_PER_FRAME: usize = 100_000;
+
+/// Runs a ROM until the frontend has received `frame_count` frames and returns the last one
+fn run_to_frame(rom_path: &str, frame_count: usize) -> Box<Framebuffer> {
+	let (sender, receiver) = channel();
+	let mut device = Device::new(sender);
+	device.load(&fs::read(rom_path).unwrap()).unwrap();
+
+	let mut frames = 0;
+	for _ in 0..MAX_TICKS_PER_FRAME * frame_count {
+		device.tick();
+		if let Ok(image_data) = receiver.try_recv() {
+			frames += 1;
+			if frames == frame_count {
+				return image_data.frame;
+			}
+		}
+	}
+
+	panic!("{rom_path} only produced {frames} of {frame_count} frames within {} ticks", MAX_TICKS_PER_FRAME * frame_count);
+}
+
+/// One line of digits per row, from 0 for white to 3 for black
+fn frame_to_text(frame: &Framebuffer) -> Vec<String> {
+	frame.iter()
+		.map(|row| row.iter().map(|color| match color {
+			Color::White => '0',
+			Color::LightGray => '1',
+			Color::DarkGray => '2',
+			Color::Black => '3',
+		}).collect())
+		.collect()
+}
+
+fn assert_frame_matches(frame: &Framebuffer, expected_path: &str) {
+	let expected = fs::read_to_string(expected_path).unwrap();
+	let actual = frame_to_text(frame);
+	assert_eq!(actual.len(), expected.lines().count(), "{expected_path} has a different number of rows than the frame");
+	for (line, (actual, expected)) in actual.iter().zip(expected.lines()).enumerate() {
+		assert_eq!(actual, expected, "Line {line} differs from {expected_path}");
+	}
+}
+
+#[test]
+fn test_m2_win_en_toggle() {
+	// The window is toggled in every mode 2, and the VBlank handler turns it back on. The mode 2 interrupt at the
+	// start of VBlank turns it off again, so the window is drawn on even lines, one window row per drawn line
+	let frame = run_to_frame("src/roms/m2_win_en_toggle.gb", 15);
+	assert_frame_matches(&frame, "tests/expected/m2_win_en_toggle.txt");
+}